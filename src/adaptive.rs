@@ -0,0 +1,47 @@
+use tracer;
+use convergence;
+
+/// Width and height of the tiles that share a sample count, one workgroup of the tracer
+pub const TILE: u32 = 16;
+/// The most samples a tile gets per frame
+pub const MAX_SAMPLES: u32 = 4;
+/// Every tile gets a single sample until the error estimate can be trusted
+const WARMUP: u32 = 8;
+
+/// Number of tiles needed to cover an image, which is also the length of the sample mask
+pub fn tile_count(dimensions: [u32; 2]) -> usize {
+    ((dimensions[0] / TILE) * (dimensions[1] / TILE)) as usize
+}
+
+/// Decides how many samples every tile takes in the next frame. Tiles get samples
+/// in proportion to their relative error, so a tile with the average error gets one
+/// and tiles up to `MAX_SAMPLES` times noisier get more. `samples` is the number of
+/// frames since the image started over, each pixel counts its own in `aovs`.
+pub fn sample_mask(accum: &[[f32; 4]], aovs: &[tracer::ty::Aov], dimensions: [u32; 2], samples: u32) -> Vec<u32> {
+    let tiles = [dimensions[0] / TILE, dimensions[1] / TILE];
+    if samples < WARMUP {
+        return vec![1; tile_count(dimensions)];
+    }
+
+    let mut errors = Vec::with_capacity(tile_count(dimensions));
+    for ty in 0..tiles[1] {
+        for tx in 0..tiles[0] {
+            let mut sum = 0.0;
+            for y in ty * TILE..(ty + 1) * TILE {
+                for x in tx * TILE..(tx + 1) * TILE {
+                    let i = (x + y * dimensions[0]) as usize;
+                    sum += convergence::relative_error(&accum[i], tracer::frames(&aovs[i]));
+                }
+            }
+            errors.push(sum / (TILE * TILE) as f32);
+        }
+    }
+
+    let mean = errors.iter().sum::<f32>() / errors.len().max(1) as f32;
+    if mean <= 0.0 {
+        return vec![1; errors.len()];
+    }
+    errors.iter()
+        .map(|e| ((e / mean).round() as u32).max(1).min(MAX_SAMPLES))
+        .collect()
+}
@@ -0,0 +1,277 @@
+use tracer;
+use scene::Scene;
+use filter::Filter;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use nalgebra::{Matrix4, Rotation3, Vector3};
+
+/// Keyframes of a 3D value, linearly interpolated and clamped at the ends.
+#[derive(Default)]
+pub struct Curve {
+    keys: Vec<(f32, Vector3<f32>)>,
+}
+
+impl Curve {
+    fn insert(&mut self, time: f32, value: Vector3<f32>) {
+        let i = self.keys.iter().position(|&(t, _)| t > time).unwrap_or(
+            self.keys.len(),
+        );
+        self.keys.insert(i, (time, value));
+    }
+
+    /// The interpolated value at `time`, or `None` if there are no keys
+    pub fn sample(&self, time: f32) -> Option<Vector3<f32>> {
+        match self.keys.iter().position(|&(t, _)| t > time) {
+            Some(0) => Some(self.keys[0].1),
+            Some(i) => {
+                let (t0, v0) = self.keys[i - 1];
+                let (t1, v1) = self.keys[i];
+                Some(v0 + (v1 - v0) * ((time - t0) / (t1 - t0)))
+            }
+            None => self.keys.last().map(|&(_, v)| v),
+        }
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keys.last().map(|&(t, _)| t).unwrap_or(0.0)
+    }
+}
+
+/// Position, rotation (euler angles in degrees) and scale of an object
+#[derive(Default)]
+pub struct Track {
+    pub position: Curve,
+    pub rotation: Curve,
+    pub scale: Curve,
+}
+
+impl Track {
+    pub fn transform(&self, time: f32) -> Matrix4<f32> {
+        let position = self.position.sample(time).unwrap_or(Vector3::new(0.0, 0.0, 0.0));
+        let rotation = self.rotation.sample(time).unwrap_or(Vector3::new(0.0, 0.0, 0.0)) *
+            (::std::f32::consts::PI / 180.0);
+        let scale = self.scale.sample(time).unwrap_or(Vector3::new(1.0, 1.0, 1.0));
+        Matrix4::new_translation(&position) *
+            Rotation3::from_euler_angles(rotation.x, rotation.y, rotation.z).to_homogeneous() *
+            Matrix4::new_nonuniform_scaling(&scale)
+    }
+
+    fn duration(&self) -> f32 {
+        self.position.duration().max(self.rotation.duration()).max(
+            self.scale.duration(),
+        )
+    }
+}
+
+/// Keyframed animation of the camera and of scene instances.
+///
+/// The file has one keyframe per line, times are in seconds:
+///
+/// ```text
+/// # camera <position|target> <time> <x> <y> <z>
+/// camera position 0.0  0.0 3.0 20.0
+/// camera target   2.0  0.0 3.0 1.0
+/// # instance <index> <position|rotation|scale> <time> <x> <y> <z>
+/// instance 0 rotation 1.0  0.0 90.0 0.0
+/// # filter <box|tent|gaussian|mitchell|blackman-harris> [radius]
+/// filter mitchell 2.0
+/// ```
+///
+/// Instances without keyframes keep their transform. Animated instances get
+/// an absolute transform, with identity for channels that have no keys.
+#[derive(Default)]
+pub struct Animation {
+    pub camera_position: Curve,
+    pub camera_target: Curve,
+    pub instances: Vec<(usize, Track)>,
+    /// pixel reconstruction filter of the sequence, and maybe its radius
+    pub filter: Option<(Filter, Option<f32>)>,
+}
+
+impl Animation {
+    pub fn load(path: &Path) -> Result<Animation, String> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut animation = Animation::default();
+
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| format!("{}: {}", path.display(), e))?;
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() || words[0].starts_with('#') {
+                continue;
+            }
+            animation.parse_line(&words).map_err(|e| {
+                format!("{}:{}: {}", path.display(), n + 1, e)
+            })?;
+        }
+        Ok(animation)
+    }
+
+    fn parse_line(&mut self, words: &[&str]) -> Result<(), String> {
+        let numbers = |words: &[&str]| -> Result<(f32, Vector3<f32>), String> {
+            let values = words
+                .iter()
+                .map(|w| w.parse::<f32>().map_err(|_| format!("expected a number, got `{}`", w)))
+                .collect::<Result<Vec<_>, _>>()?;
+            if values.len() != 4 {
+                return Err("expected <time> <x> <y> <z>".to_string());
+            }
+            Ok((values[0], Vector3::new(values[1], values[2], values[3])))
+        };
+
+        match words[0] {
+            "camera" if words.len() > 1 => {
+                let (time, value) = numbers(&words[2..])?;
+                match words[1] {
+                    "position" => self.camera_position.insert(time, value),
+                    "target" => self.camera_target.insert(time, value),
+                    channel => return Err(format!("unknown camera channel `{}`", channel)),
+                }
+            }
+            "instance" if words.len() > 2 => {
+                let index = words[1].parse::<usize>().map_err(|_| {
+                    format!("expected an instance index, got `{}`", words[1])
+                })?;
+                let (time, value) = numbers(&words[3..])?;
+                let track = match self.instances.iter().position(|&(i, _)| i == index) {
+                    Some(i) => &mut self.instances[i].1,
+                    None => {
+                        self.instances.push((index, Track::default()));
+                        &mut self.instances.last_mut().unwrap().1
+                    }
+                };
+                match words[2] {
+                    "position" => track.position.insert(time, value),
+                    "rotation" => track.rotation.insert(time, value),
+                    "scale" => track.scale.insert(time, value),
+                    channel => return Err(format!("unknown instance channel `{}`", channel)),
+                }
+            }
+            "filter" if words.len() == 2 || words.len() == 3 => {
+                let filter = Filter::from_name(words[1]).ok_or_else(|| format!("unknown filter `{}`", words[1]))?;
+                let radius = match words.get(2) {
+                    Some(w) => match w.parse::<f32>() {
+                        Ok(radius) if radius > 0.0 => Some(radius),
+                        _ => return Err(format!("expected a positive radius, got `{}`", w)),
+                    },
+                    None => None,
+                };
+                self.filter = Some((filter, radius));
+            }
+            _ => return Err(format!("unknown keyframe `{}`", words.join(" "))),
+        }
+        Ok(())
+    }
+
+    /// Time of the last keyframe
+    pub fn duration(&self) -> f32 {
+        self.instances.iter().map(|&(_, ref track)| track.duration()).fold(
+            self.camera_position.duration().max(self.camera_target.duration()),
+            f32::max,
+        )
+    }
+
+    /// Moves the animated instances and the camera to their state at `time`.
+    /// They move on to their state at `time + shutter` while the shutter is open,
+    /// where `camera_end` is the camera when it closes.
+    /// Call `Scene::refit` afterwards to update the BVH.
+    pub fn apply(
+        &self,
+        time: f32,
+        shutter: f32,
+        scene: &mut Scene,
+        camera: &mut tracer::ty::Camera,
+        camera_end: &mut tracer::ty::Camera,
+    ) {
+        for &(index, ref track) in &self.instances {
+            if index < scene.instances.len() {
+                scene.set_motion(index, track.transform(time), track.transform(time + shutter));
+            }
+        }
+        self.place_camera(time, camera);
+        *camera_end = *camera;
+        self.place_camera(time + shutter, camera_end);
+    }
+
+    fn place_camera(&self, time: f32, camera: &mut tracer::ty::Camera) {
+        if let Some(position) = self.camera_position.sample(time) {
+            camera.origin = position.into();
+        }
+        if let Some(target) = self.camera_target.sample(time) {
+            camera.target = target.into();
+        }
+        camera.update();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve(keys: &[(f32, f32)]) -> Curve {
+        let mut curve = Curve::default();
+        for &(time, x) in keys {
+            curve.insert(time, Vector3::new(x, 0.0, 0.0));
+        }
+        curve
+    }
+
+    #[test]
+    fn sample_interpolates_between_keys() {
+        let curve = curve(&[(0.0, 0.0), (2.0, 4.0)]);
+        assert_eq!(curve.sample(0.5), Some(Vector3::new(1.0, 0.0, 0.0)));
+        assert_eq!(curve.sample(2.0), Some(Vector3::new(4.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn sample_clamps_at_the_ends() {
+        let curve = curve(&[(1.0, 1.0), (2.0, 3.0)]);
+        assert_eq!(curve.sample(0.0), Some(Vector3::new(1.0, 0.0, 0.0)));
+        assert_eq!(curve.sample(5.0), Some(Vector3::new(3.0, 0.0, 0.0)));
+        assert_eq!(Curve::default().sample(1.0), None);
+    }
+
+    #[test]
+    fn keys_are_sorted_by_time() {
+        let curve = curve(&[(2.0, 4.0), (0.0, 0.0), (1.0, 10.0)]);
+        assert_eq!(curve.sample(0.5), Some(Vector3::new(5.0, 0.0, 0.0)));
+        assert_eq!(curve.duration(), 2.0);
+    }
+
+    fn parse(lines: &[&str]) -> Result<Animation, String> {
+        let mut animation = Animation::default();
+        for line in lines {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            animation.parse_line(&words)?;
+        }
+        Ok(animation)
+    }
+
+    #[test]
+    fn parses_keyframes() {
+        let animation = parse(&[
+            "camera position 0.0  0.0 3.0 20.0",
+            "camera target 2.0  0.0 3.0 1.0",
+            "instance 1 rotation 1.0  0.0 90.0 0.0",
+            "instance 1 scale 3.0  2.0 2.0 2.0",
+            "filter mitchell 2.5",
+        ]).unwrap();
+        assert_eq!(animation.camera_position.sample(0.0), Some(Vector3::new(0.0, 3.0, 20.0)));
+        assert_eq!(animation.camera_target.sample(0.0), Some(Vector3::new(0.0, 3.0, 1.0)));
+        assert_eq!(animation.instances.len(), 1);
+        assert_eq!(animation.instances[0].0, 1);
+        assert_eq!(animation.filter, Some((Filter::Mitchell, Some(2.5))));
+        assert_eq!(animation.duration(), 3.0);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(parse(&["camera position 0.0 1.0 2.0"]).is_err());
+        assert!(parse(&["camera up 0.0 0.0 1.0 0.0"]).is_err());
+        assert!(parse(&["instance x position 0.0 0.0 0.0 0.0"]).is_err());
+        assert!(parse(&["instance 0 position 0.0 a 0.0 0.0"]).is_err());
+        assert!(parse(&["filter sinc"]).is_err());
+        assert!(parse(&["filter box 0"]).is_err());
+        assert!(parse(&["light 0.0 0.0 0.0 0.0"]).is_err());
+    }
+}
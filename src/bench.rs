@@ -0,0 +1,179 @@
+use tracer;
+use compute::ComputePart;
+use bvh_stats;
+use room;
+use debug_view::DebugView;
+use options::Options;
+use scene::Scene;
+use devices;
+use timing::Timer;
+use logging::{VULKAN, PERF, OUTPUT};
+use nalgebra::Matrix4;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBuffer};
+use vulkano::device::{Device, DeviceExtensions};
+use vulkano::format::R8G8B8A8Unorm;
+use vulkano::image::{Dimensions, StorageImage};
+use vulkano::instance::{Instance, InstanceExtensions};
+use vulkano::sync::GpuFuture;
+
+/// Name and model of every benchmark scene; the Cornell box is the bare room
+const SCENES: [(&str, Option<&str>); 3] = [
+    ("cube", Some("assets/cube.obj")),
+    ("teapot", Some("assets/teapot.obj")),
+    ("cornell-box", None),
+];
+const DIMENSIONS: [u32; 2] = [512, 512];
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1.0e3 + duration.subsec_nanos() as f64 / 1.0e6
+}
+
+/// `s` as the inside of a JSON string
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders every scene of `SCENES` for `options.samples` samples per pixel without
+/// a window, and writes the build time, BVH statistics, GPU frame times and ray
+/// throughput of each to bench.json, so runs on different commits can be compared.
+pub fn run(options: &Options) {
+    let instance = Instance::new(None, &InstanceExtensions::none(), None).expect("failed to create instance");
+    let physical = devices::select(&instance, options.device.as_ref().map(|s| s.as_str()));
+    info!(target: VULKAN, "benchmarking on {}", physical.name());
+    let family = physical.queue_families()
+        .find(|q| q.supports_compute())
+        .expect("couldn't find a compute queue family");
+    let (device, mut queues) = Device::new(
+        physical,
+        physical.supported_features(),
+        &DeviceExtensions::none(),
+        Some((family, 0.5)).into_iter(),
+    ).expect("failed to create device");
+    let queue = queues.next().unwrap();
+    let image = StorageImage::new(
+        device.clone(),
+        Dimensions::Dim2d { width: DIMENSIONS[0], height: DIMENSIONS[1] },
+        R8G8B8A8Unorm,
+        Some(family),
+    ).unwrap();
+    // the same queue for both stages, there is no draw
+    let timer = Timer::new(&device, &queue, &queue, physical.limits().timestamp_period());
+
+    let mut results = Vec::new();
+    for &(name, model) in &SCENES {
+        let start = Instant::now();
+        let mut scene = Scene::new();
+        if let Some(model) = model {
+            // never from the cache, so we measure the builder
+            let mesh = scene.load_mesh(Path::new(model), room::model_material(), options.sbvh, false);
+            scene.add_instance(mesh, Matrix4::identity());
+        }
+        scene.add_sphere(room::sphere());
+        scene.build_top_level();
+        if options.bvh_width > 2 {
+            scene.build_wide(options.bvh_width as usize);
+        }
+        let build_time = millis(start.elapsed());
+
+        let planes = room::planes();
+        let num_planes = planes.len() as u32;
+        // every frame waits for the one before, so both slots can share the image
+        let mut compute = ComputePart::new(
+            &device,
+            [image.clone(), image.clone()],
+            planes,
+            &scene,
+            options.filter_table(None),
+            &queue,
+        );
+
+        let mut frame_times = Vec::with_capacity(options.samples as usize);
+        let mut rays = 0u64;
+        for frame_num in 1..options.samples + 1 {
+            let mut cbb = AutoCommandBufferBuilder::new(device.clone(), queue.family()).unwrap();
+            cbb = compute.reset_ray_count(cbb);
+            cbb = compute.render(
+                cbb,
+                0,
+                DIMENSIONS,
+                tracer::ty::Input {
+                    camera: room::camera(),
+                    num_planes,
+                    num_triangles: scene.triangles.len() as u32,
+                    frame_num,
+                    light: room::light(),
+                    top_node_length: scene.top_nodes.len() as u32,
+                    bvh_width: options.bvh_width,
+                    debug_view: DebugView::None as u32,
+                    debug_scale: DebugView::None.scale(),
+                    sampler_kind: options.sampler as u32,
+                    camera_end: room::camera(),
+                    _dummy0: [0; 12],
+                    _dummy1: [0; 4],
+                },
+            );
+            cbb = compute.read_ray_count(cbb, 0);
+            let cb = cbb.build().unwrap();
+
+            let start = Instant::now();
+            if let Some(ref timer) = timer {
+                timer.before_trace(&queue, 0);
+            }
+            let fence = cb.execute(queue.clone()).unwrap()
+                .then_signal_fence_and_flush().unwrap();
+            if let Some(ref timer) = timer {
+                timer.after_trace(&queue, 0);
+            }
+            fence.wait(None).unwrap();
+            // without timestamps the CPU time is the best we have, it includes the submission
+            frame_times.push(match timer {
+                Some(ref timer) => timer.wait_trace(0),
+                None => millis(start.elapsed()),
+            });
+            rays += compute.rays(0) as u64;
+        }
+
+        let total: f64 = frame_times.iter().sum();
+        let mrays = rays as f64 / (total * 1.0e3);
+        info!(target: PERF, "{}: built in {:.1} ms, {:.3} ms per frame, {:.1} Mrays/s",
+              name, build_time, total / frame_times.len() as f64, mrays);
+        results.push(format!(
+            "{{\"name\": \"{}\", \"triangles\": {}, \"build_ms\": {}, \"bvh\": {}, \"samples\": {}, \
+             \"frame_ms\": [{}], \"mean_frame_ms\": {}, \"rays\": {}, \"mrays_per_second\": {}}}",
+            name,
+            scene.triangles.len(),
+            build_time,
+            bvh_stats::scene_json(&scene),
+            options.samples,
+            frame_times.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(", "),
+            total / frame_times.len() as f64,
+            rays,
+            mrays
+        ));
+    }
+
+    let path = Path::new("bench.json");
+    let mut file = File::create(path).expect("failed to write bench.json");
+    writeln!(
+        file,
+        "{{\"device\": \"{}\", \"sbvh\": {}, \"bvh_width\": {}, \"scenes\": [{}]}}",
+        escape(physical.name()),
+        options.sbvh,
+        options.bvh_width,
+        results.join(", ")
+    ).expect("failed to write bench.json");
+    info!(target: OUTPUT, "wrote {}", path.display());
+}
@@ -0,0 +1,219 @@
+use tracer;
+use refit::{LEAF, children};
+use scene::Scene;
+use logging::BVH;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::path::Path;
+
+/// Costs of the surface area heuristic, relative to one triangle test
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+
+/// Quality statistics of a flattened rope BVH
+pub struct BvhStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    /// number of leaves at each depth
+    pub depth_histogram: Vec<usize>,
+    /// average depth of the leaves
+    pub mean_leaf_depth: f32,
+    /// number of leaves by the number of shapes they contain. Every leaf of the
+    /// flattened layout refers to exactly one shape for now
+    pub leaf_sizes: Vec<usize>,
+    /// expected cost of a random ray, according to the surface area heuristic
+    pub sah_cost: f32,
+    /// sum of the surface areas of all nodes
+    pub surface_area: f32,
+    /// sum of the surface areas of the overlap between siblings
+    pub overlap_area: f32,
+    /// size of the nodes; `memory_bytes` is the size of everything uploaded
+    pub node_bytes: usize,
+}
+
+fn surface_area(aabb: &tracer::ty::AABB) -> f32 {
+    let d = [
+        (aabb.max[0] - aabb.min[0]).max(0.0),
+        (aabb.max[1] - aabb.min[1]).max(0.0),
+        (aabb.max[2] - aabb.min[2]).max(0.0),
+    ];
+    2.0 * (d[0] * d[1] + d[1] * d[2] + d[2] * d[0])
+}
+
+fn overlap_area(a: &tracer::ty::AABB, b: &tracer::ty::AABB) -> f32 {
+    let mut overlap = *a;
+    for i in 0..3 {
+        overlap.min[i] = a.min[i].max(b.min[i]);
+        overlap.max[i] = a.max[i].min(b.max[i]);
+        if overlap.min[i] > overlap.max[i] {
+            return 0.0;
+        }
+    }
+    surface_area(&overlap)
+}
+
+impl BvhStats {
+    pub fn compute(nodes: &[tracer::ty::Node]) -> BvhStats {
+        let mut depth = vec![0; nodes.len()];
+        let mut stats = BvhStats {
+            nodes: nodes.len(),
+            leaves: 0,
+            depth_histogram: Vec::new(),
+            mean_leaf_depth: 0.0,
+            leaf_sizes: Vec::new(),
+            sah_cost: 0.0,
+            surface_area: 0.0,
+            overlap_area: 0.0,
+            node_bytes: nodes.len() * mem::size_of::<tracer::ty::Node>(),
+        };
+        if nodes.is_empty() {
+            return stats;
+        }
+
+        let root_area = surface_area(&nodes[0].aabb);
+        let mut cost = 0.0;
+
+        // parents come before their children, so depths are known when we get to a node
+        for i in 0..nodes.len() {
+            let area = surface_area(&nodes[i].aabb);
+            stats.surface_area += area;
+
+            if nodes[i].entry_index == LEAF {
+                stats.leaves += 1;
+                if stats.depth_histogram.len() <= depth[i] {
+                    stats.depth_histogram.resize(depth[i] + 1, 0);
+                }
+                stats.depth_histogram[depth[i]] += 1;
+                stats.mean_leaf_depth += depth[i] as f32;
+                let shapes = 1;
+                if stats.leaf_sizes.len() <= shapes {
+                    stats.leaf_sizes.resize(shapes + 1, 0);
+                }
+                stats.leaf_sizes[shapes] += 1;
+                cost += INTERSECTION_COST * shapes as f32 * area;
+            } else {
+                let children = children(nodes, i);
+                for (n, &a) in children.iter().enumerate() {
+                    depth[a] = depth[i] + 1;
+                    for &b in &children[n + 1..] {
+                        stats.overlap_area += overlap_area(&nodes[a].aabb, &nodes[b].aabb);
+                    }
+                }
+                cost += TRAVERSAL_COST * area;
+            }
+        }
+
+        if stats.leaves > 0 {
+            stats.mean_leaf_depth /= stats.leaves as f32;
+        }
+        if root_area > 0.0 && root_area.is_finite() {
+            stats.sah_cost = cost / root_area;
+        }
+        stats
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.depth_histogram.len().saturating_sub(1)
+    }
+
+    pub fn to_json(&self) -> String {
+        let list = |values: &[usize]| {
+            values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")
+        };
+        format!(
+            "{{\"nodes\": {}, \"leaves\": {}, \"max_depth\": {}, \"depth_histogram\": [{}], \
+             \"mean_leaf_depth\": {}, \"leaf_sizes\": [{}], \"sah_cost\": {}, \"surface_area\": {}, \
+             \"overlap_area\": {}, \"node_bytes\": {}}}",
+            self.nodes,
+            self.leaves,
+            self.max_depth(),
+            list(&self.depth_histogram),
+            number(self.mean_leaf_depth),
+            list(&self.leaf_sizes),
+            number(self.sah_cost),
+            number(self.surface_area),
+            number(self.overlap_area),
+            self.node_bytes
+        )
+    }
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "  nodes:         {} ({} leaves)", self.nodes, self.leaves)?;
+        writeln!(f, "  node memory:   {:.2} MiB", mib(self.node_bytes))?;
+        writeln!(f, "  SAH cost:      {:.2}", self.sah_cost)?;
+        writeln!(f, "  surface area:  {:.2} total, {:.2} overlapping", self.surface_area, self.overlap_area)?;
+        writeln!(f, "  max depth:     {} ({:.1} on average)", self.max_depth(), self.mean_leaf_depth)?;
+        writeln!(f, "  leaves per depth:")?;
+        for (depth, &count) in self.depth_histogram.iter().enumerate() {
+            if count > 0 {
+                writeln!(f, "    {:3}: {}", depth, count)?;
+            }
+        }
+        writeln!(f, "  leaves per size:")?;
+        for (size, &count) in self.leaf_sizes.iter().enumerate() {
+            if count > 0 {
+                writeln!(f, "    {:3}: {}", size, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// JSON has no NaN or infinity, which the areas of degenerate trees can be
+fn number(value: f32) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+fn mib(bytes: usize) -> f32 {
+    bytes as f32 / (1024.0 * 1024.0)
+}
+
+/// Size of all BVH buffers uploaded to the GPU: the triangles, the mesh nodes,
+/// the instances, the top level nodes and the wide nodes
+pub fn memory_bytes(scene: &Scene) -> usize {
+    scene.triangles.len() * mem::size_of::<tracer::ty::Triangle>()
+        + scene.nodes.len() * mem::size_of::<tracer::ty::Node>()
+        + scene.instances.len() * mem::size_of::<tracer::ty::Instance>()
+        + scene.top_nodes.len() * mem::size_of::<tracer::ty::Node>()
+        + scene.wide_nodes.len() * mem::size_of::<tracer::ty::WideNode>()
+}
+
+/// The statistics of every mesh BVH and of the top level BVH as JSON
+pub fn scene_json(scene: &Scene) -> String {
+    let meshes: Vec<String> = scene.meshes.iter()
+        .map(|mesh| {
+            let start = mesh.node_offset as usize;
+            BvhStats::compute(&scene.nodes[start..start + mesh.node_length as usize]).to_json()
+        })
+        .collect();
+    format!(
+        "{{\"meshes\": [{}], \"top_level\": {}, \"memory_bytes\": {}}}",
+        meshes.join(", "),
+        BvhStats::compute(&scene.top_nodes).to_json(),
+        memory_bytes(scene)
+    )
+}
+
+/// Prints the statistics of every mesh BVH and of the top level BVH,
+/// and writes them to `path` as JSON.
+pub fn report(scene: &Scene, path: &Path) -> io::Result<()> {
+    for (i, mesh) in scene.meshes.iter().enumerate() {
+        let start = mesh.node_offset as usize;
+        let stats = BvhStats::compute(&scene.nodes[start..start + mesh.node_length as usize]);
+        info!(target: BVH, "BVH of mesh {} ({} triangles):\n{}", i, mesh.triangle_count, stats);
+    }
+    let top_level = BvhStats::compute(&scene.top_nodes);
+    info!(target: BVH, "top level BVH ({} instances):\n{}", scene.instances.len(), top_level);
+    info!(target: BVH, "{:.2} MiB of BVH buffers in total", mib(memory_bytes(scene)));
+
+    let mut file = File::create(path)?;
+    writeln!(file, "{}", scene_json(scene))
+}
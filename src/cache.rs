@@ -0,0 +1,113 @@
+use tracer;
+use std::fs::{self, File};
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+
+/// Bump this whenever the BVH builder or the flattened layout changes,
+/// so old cache files are not used anymore.
+const FORMAT_VERSION: u64 = 1;
+const MAGIC: [u8; 8] = *b"RTBVHCCH";
+const CACHE_DIR: &str = "bvh-cache";
+
+/// Cache files are raw dumps of the GPU structs. The struct sizes are stored
+/// as well, so a change in the shader layout invalidates the cache.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Header {
+    magic: [u8; 8],
+    key: u64,
+    triangle_size: u64,
+    node_size: u64,
+    triangles: u64,
+    nodes: u64,
+}
+
+/// 64 bit FNV-1a, which is stable across runs and compiler versions
+/// unlike the hasher of the standard library.
+pub struct Hasher(u64);
+
+impl Hasher {
+    pub fn new() -> Hasher {
+        let mut hasher = Hasher(0xcbf29ce484222325);
+        hasher.write(as_bytes(&[FORMAT_VERSION]));
+        hasher
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+pub fn as_bytes<T: Copy>(data: &[T]) -> &[u8] {
+    unsafe { slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * mem::size_of::<T>()) }
+}
+
+/// Reads `len` values of `T`. A truncated or corrupt file is an error, and the
+/// length from the file is only trusted once that many bytes were read.
+fn read_vec<T: Copy, R: Read>(reader: &mut R, len: usize) -> io::Result<Vec<T>> {
+    let size = len.checked_mul(mem::size_of::<T>())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "length out of range"))?;
+    let mut bytes = Vec::new();
+    reader.take(size as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != size {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "cache file is truncated"));
+    }
+    let mut data = Vec::with_capacity(len);
+    unsafe {
+        // the GPU structs are plain data, any bytes are a valid value
+        ptr::copy_nonoverlapping(bytes.as_ptr(), data.as_mut_ptr() as *mut u8, size);
+        data.set_len(len);
+    }
+    Ok(data)
+}
+
+fn path(key: u64) -> PathBuf {
+    Path::new(CACHE_DIR).join(format!("{:016x}.bin", key))
+}
+
+/// Reads the triangles and flattened BVH stored under `key`, if there are any.
+pub fn load(key: u64) -> Option<(Vec<tracer::ty::Triangle>, Vec<tracer::ty::Node>)> {
+    let mut reader = BufReader::new(File::open(path(key)).ok()?);
+    let header = read_vec::<Header, _>(&mut reader, 1).ok()?[0];
+    if header.magic != MAGIC || header.key != key ||
+        header.triangle_size != mem::size_of::<tracer::ty::Triangle>() as u64 ||
+        header.node_size != mem::size_of::<tracer::ty::Node>() as u64
+    {
+        return None;
+    }
+    let triangles = read_vec(&mut reader, header.triangles as usize).ok()?;
+    let nodes = read_vec(&mut reader, header.nodes as usize).ok()?;
+    Some((triangles, nodes))
+}
+
+/// Stores the triangles and flattened BVH under `key`.
+pub fn store(
+    key: u64,
+    triangles: &[tracer::ty::Triangle],
+    nodes: &[tracer::ty::Node],
+) -> io::Result<()> {
+    fs::create_dir_all(CACHE_DIR)?;
+    let header = Header {
+        magic: MAGIC,
+        key,
+        triangle_size: mem::size_of::<tracer::ty::Triangle>() as u64,
+        node_size: mem::size_of::<tracer::ty::Node>() as u64,
+        triangles: triangles.len() as u64,
+        nodes: nodes.len() as u64,
+    };
+    let mut writer = BufWriter::new(File::create(path(key))?);
+    writer.write_all(as_bytes(&[header]))?;
+    writer.write_all(as_bytes(triangles))?;
+    writer.write_all(as_bytes(nodes))?;
+    writer.flush()
+}
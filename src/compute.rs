@@ -1,89 +1,335 @@
 
 use tracer;
+use exr;
+use denoise;
+use convergence;
+use adaptive;
+use scene::{Scene, Changes};
 use std::sync::Arc;
-use vulkano::buffer::{BufferUsage, CpuBufferPool, CpuAccessibleBuffer};
-use vulkano::command_buffer::AutoCommandBufferBuilder;
+use std::ops::Range;
+use std::path::Path;
+use image;
+use vulkano::buffer::{BufferUsage, CpuBufferPool, CpuAccessibleBuffer, DeviceLocalBuffer, ImmutableBuffer};
+use vulkano::buffer::TypedBufferAccess;
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBuffer};
 use vulkano::descriptor::descriptor_set::DescriptorSet;
 use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
-use vulkano::device::Device;
+use vulkano::device::{Device, Queue};
 use vulkano::image::traits::ImageViewAccess;
-use vulkano::instance::QueueFamily;
+use vulkano::memory::Content;
 use vulkano::pipeline::ComputePipeline;
 use vulkano::pipeline::ComputePipelineAbstract;
+use vulkano::sync::GpuFuture;
 
 
+/// Scene data lives in device-local memory. What never changes is uploaded once into
+/// immutable buffers, what can change is updated through staging buffers.
 pub struct ComputePart<I: 'static + ImageViewAccess + Send + Sync> {
+    device: Arc<Device>,
     pipeline: Arc<ComputePipelineAbstract + Send + Sync>,
-    image: Arc<I>,
+    /// the output image of each slot, one is traced while the other is drawn
+    images: [Arc<I>; 2],
     input_pool: CpuBufferPool<tracer::ty::Input>,
-    spheres: Arc<CpuAccessibleBuffer<[tracer::ty::Sphere]>>,
-    planes: Arc<CpuAccessibleBuffer<[tracer::ty::Plane]>>,
-    triangles: Arc<CpuAccessibleBuffer<[tracer::ty::Triangle]>>,
-    nodes: Arc<CpuAccessibleBuffer<[tracer::ty::Node]>>,
-    accum: Arc<CpuAccessibleBuffer<[[f32;4]]>>,
+    spheres: Arc<ImmutableBuffer<[tracer::ty::Sphere]>>,
+    planes: Arc<ImmutableBuffer<[tracer::ty::Plane]>>,
+    triangles: Arc<ImmutableBuffer<[tracer::ty::Triangle]>>,
+    nodes: Arc<ImmutableBuffer<[tracer::ty::Node]>>,
+    instances: Arc<DeviceLocalBuffer<[tracer::ty::Instance]>>,
+    top_nodes: Arc<DeviceLocalBuffer<[tracer::ty::Node]>>,
+    wide_nodes: Arc<ImmutableBuffer<[tracer::ty::WideNode]>>,
+    /// every dispatch reads one of each pair and writes the other
+    accum: [Arc<DeviceLocalBuffer<[[f32;4]]>>; 2],
+    aovs: [Arc<DeviceLocalBuffer<[tracer::ty::Aov]>>; 2],
+    /// the index of the pair the last dispatch wrote
+    current: usize,
+    sample_mask: Arc<CpuAccessibleBuffer<[u32]>>,
+    filter_table: Arc<ImmutableBuffer<[[f32;2]]>>,
+    ray_count: Arc<DeviceLocalBuffer<u32>>,
+    /// samples taken by every pixel, cleared by the tracer on the first frame
+    sample_count: Arc<DeviceLocalBuffer<[u32]>>,
+    /// copies of `ray_count`, like `readback`
+    ray_readback: [Arc<CpuAccessibleBuffer<u32>>; 2],
+    /// copies of `accum` the CPU reads, so it can look at one frame while the next is traced
+    readback: [Arc<CpuAccessibleBuffer<[[f32;4]]>>; 2],
+    /// copies of `aovs`, like `readback`
+    aov_readback: [Arc<CpuAccessibleBuffer<[tracer::ty::Aov]>>; 2],
 }
 
 impl<I: 'static + ImageViewAccess + Send + Sync> ComputePart<I> {
-    pub fn new(device: &Arc<Device>, image: Arc<I>, spheres: Vec<tracer::ty::Sphere>, planes: Vec<tracer::ty::Plane>, triangles: Vec<tracer::ty::Triangle>, nodes: Vec<tracer::ty::Node>, _family: QueueFamily) -> ComputePart<I> {
+    /// `images` are the output images of the two slots.
+    /// `filter_table` is the table of the reconstruction filter, see `Filter::table`
+    /// `queue` is the queue the tracer runs on
+    pub fn new(device: &Arc<Device>, images: [Arc<I>; 2], planes: Vec<tracer::ty::Plane>, scene: &Scene, filter_table: Vec<[f32;2]>, queue: &Arc<Queue>) -> ComputePart<I> {
         let shader = tracer::Shader::load(device.clone()).expect("failed to create shader module");
+        let constants = tracer::SpecializationConstants {
+            wide_stack_size: scene.wide_stack_size(),
+        };
         let pipeline = Arc::new(
-            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &())
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &constants)
                 .expect("failed to create compute pipeline"),
         );
 
         let input_pool = CpuBufferPool::uniform_buffer(device.clone());
-        let spheres = CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), spheres.into_iter()).unwrap();
-        let planes = CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), planes.into_iter()).unwrap();
-        let triangles = CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), triangles.into_iter()).unwrap();
-        let nodes = CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), nodes.into_iter()).unwrap();
+        let spheres = immutable(queue, scene.spheres.clone());
+        let planes = immutable(queue, planes);
+        let triangles = immutable(queue, scene.triangles.clone());
+        let nodes = immutable(queue, scene.nodes.clone());
+        // the binding needs a buffer even when we only use the binary BVH
+        let wide_nodes = immutable(queue, scene.wide_nodes.clone());
+        // instances move, which refits the top level BVH
+        let instances = device_local(device, queue, scene.gpu_instances());
+        let top_nodes = device_local(device, queue, scene.top_nodes.clone());
 
-        let accum = CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), (0..512*512).map(|_|[0.;4])).unwrap();
+        // the tracer clears these on the first frame
+        let accum = || DeviceLocalBuffer::array(device.clone(), 512*512, BufferUsage::all(), device.active_queue_families()).unwrap();
+        let aovs = || DeviceLocalBuffer::array(device.clone(), 512*512, BufferUsage::all(), device.active_queue_families()).unwrap();
+        let readback = [
+            CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), (0..512*512).map(|_|[0.;4])).unwrap(),
+            CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), (0..512*512).map(|_|[0.;4])).unwrap(),
+        ];
+        let aov_readback = [aov_buffer(device), aov_buffer(device)];
+        let filter_table = immutable(queue, filter_table);
+        let ray_count = DeviceLocalBuffer::new(device.clone(), BufferUsage::all(), device.active_queue_families()).unwrap();
+        let sample_count = DeviceLocalBuffer::array(device.clone(), 512*512, BufferUsage::all(), device.active_queue_families()).unwrap();
+        let ray_readback = [
+            CpuAccessibleBuffer::from_data(device.clone(), BufferUsage::all(), 0).unwrap(),
+            CpuAccessibleBuffer::from_data(device.clone(), BufferUsage::all(), 0).unwrap(),
+        ];
+        let sample_mask = CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), (0..adaptive::tile_count([512, 512])).map(|_| 1)).unwrap();
 
         ComputePart {
+            device: device.clone(),
             pipeline,
-            image,
+            images,
             input_pool,
             spheres,
             planes,
             triangles,
-            accum,
+            accum: [accum(), accum()],
+            aovs: [aovs(), aovs()],
+            current: 0,
+            sample_mask,
+            filter_table,
+            readback,
+            aov_readback,
+            ray_count,
+            ray_readback,
+            sample_count,
             nodes,
+            instances,
+            top_nodes,
+            wide_nodes,
         }
     }
-    pub fn calculate_energy(&self, framenum: u32) -> f32 {
-        let content = self.accum.read().unwrap();
-        let x: f32 = content.into_iter().map(|x| x[0] + x[1] + x[2]).sum();
-        x / (framenum as f32)
+    /// Records the upload of the parts of `scene` that changed
+    pub fn update(&self, mut builder: AutoCommandBufferBuilder, scene: &Scene, changes: &Changes) -> AutoCommandBufferBuilder {
+        if let Some(ref range) = changes.instances {
+            builder = upload(builder, &self.device, &self.instances, &scene.gpu_instances(), range.clone());
+        }
+        if changes.top_nodes {
+            builder = upload(builder, &self.device, &self.top_nodes, &scene.top_nodes, 0..scene.top_nodes.len());
+        }
+        builder
+    }
+    /// Copies the accumulated samples and the AOVs to readback buffer `slot`
+    pub fn readback(&self, builder: AutoCommandBufferBuilder, slot: usize) -> AutoCommandBufferBuilder {
+        builder.copy_buffer(self.accum(), self.readback[slot].clone()).unwrap()
+            .copy_buffer(self.aovs(), self.aov_readback[slot].clone()).unwrap()
+    }
+    /// Starts counting the rays of a frame anew
+    pub fn reset_ray_count(&self, builder: AutoCommandBufferBuilder) -> AutoCommandBufferBuilder {
+        builder.fill_buffer(self.ray_count.clone(), 0).unwrap()
+    }
+    /// Copies the number of rays traced so far to readback buffer `slot`
+    pub fn read_ray_count(&self, builder: AutoCommandBufferBuilder, slot: usize) -> AutoCommandBufferBuilder {
+        builder.copy_buffer(self.ray_count.clone(), self.ray_readback[slot].clone()).unwrap()
+    }
+    /// The GPU must be done writing readback buffer `slot`
+    pub fn rays(&self, slot: usize) -> u32 {
+        *self.ray_readback[slot].read().unwrap()
+    }
+    /// The sums the last recorded dispatch writes
+    pub fn accum(&self) -> Arc<DeviceLocalBuffer<[[f32;4]]>> {
+        self.accum[self.current].clone()
+    }
+    /// The AOVs the last recorded dispatch writes
+    pub fn aovs(&self) -> Arc<DeviceLocalBuffer<[tracer::ty::Aov]>> {
+        self.aovs[self.current].clone()
+    }
+    /// The accumulated samples and the AOVs in readback buffer `slot`, for the
+    /// `save_*` functions. The GPU must be done writing it.
+    pub fn download(&self, slot: usize) -> (Vec<[f32;4]>, Vec<tracer::ty::Aov>) {
+        let accum = self.readback[slot].read().unwrap().to_vec();
+        let aovs = self.aov_readback[slot].read().unwrap().to_vec();
+        (accum, aovs)
     }
-    /// when `scene` is not None, a new scene will be uploaded
+    /// Spends more samples of the next frame on the tiles that are still noisy.
+    /// `framenum` is the number of frames since readback buffer `slot` started over.
+    pub fn update_sample_mask(&self, slot: usize, dimensions: [u32; 2], framenum: u32) {
+        let mask = adaptive::sample_mask(
+            &self.readback[slot].read().unwrap(),
+            &self.aov_readback[slot].read().unwrap(),
+            dimensions,
+            framenum,
+        );
+        let mut content = self.sample_mask.write().unwrap();
+        content[..mask.len()].copy_from_slice(&mask);
+    }
+    /// The GPU must be done writing readback buffer `slot`
+    pub fn mean_relative_error(&self, slot: usize, dimensions: [u32; 2]) -> f32 {
+        let count = (dimensions[0] * dimensions[1]) as usize;
+        let content = self.readback[slot].read().unwrap();
+        let aovs = self.aov_readback[slot].read().unwrap();
+        convergence::mean_relative_error(&content[..count], &aovs[..count])
+    }
+    /// Records a dispatch that adds a sample to the sums and writes the image of `slot`
     pub fn render(
         &mut self,
         builder: AutoCommandBufferBuilder,
+        slot: usize,
         dimensions: [u32; 2],
         input: tracer::ty::Input,
     ) -> AutoCommandBufferBuilder {
+        let set = self.next_set(slot, input);
         builder.dispatch([dimensions[0] / 16, dimensions[1] / 16, 1],
                       self.pipeline.clone(),
-                      self.next_set(input),
+                      set,
                       ())
             .unwrap()
     }
 
+    /// Swaps the pairs of sums, the new set reads the ones the last dispatch wrote
     fn next_set(
         &mut self,
+        slot: usize,
         input: tracer::ty::Input,
     ) -> Arc<DescriptorSet + Send + Sync> {
+        let previous = self.current;
+        self.current = 1 - previous;
         Arc::new(
             PersistentDescriptorSet::start(self.pipeline.clone(), 0)
-                .add_image(self.image.clone()).unwrap()
+                .add_image(self.images[slot].clone()).unwrap()
                 .add_buffer(self.input_pool.next(input).unwrap()).unwrap()
                 .add_buffer(self.spheres.clone()).unwrap()
                 .add_buffer(self.planes.clone()).unwrap()
                 .add_buffer(self.triangles.clone()).unwrap()
-                .add_buffer(self.accum.clone()).unwrap()
+                .add_buffer(self.accum[self.current].clone()).unwrap()
                 .add_buffer(self.nodes.clone()).unwrap()
+                .add_buffer(self.instances.clone()).unwrap()
+                .add_buffer(self.top_nodes.clone()).unwrap()
+                .add_buffer(self.wide_nodes.clone()).unwrap()
+                .add_buffer(self.aovs[self.current].clone()).unwrap()
+                .add_buffer(self.sample_mask.clone()).unwrap()
+                .add_buffer(self.filter_table.clone()).unwrap()
+                .add_buffer(self.ray_count.clone()).unwrap()
+                .add_buffer(self.sample_count.clone()).unwrap()
+                .add_buffer(self.accum[previous].clone()).unwrap()
+                .add_buffer(self.aovs[previous].clone()).unwrap()
                 .build()
                 .unwrap(),
         )
     }
 }
+
+/// An immutable device-local buffer holding `data`. Vulkan has no empty buffers, so
+/// without data it holds a single default element, which the shader never reads.
+fn immutable<T>(queue: &Arc<Queue>, mut data: Vec<T>) -> Arc<ImmutableBuffer<[T]>>
+where
+    T: Copy + Default + Send + Sync + 'static,
+{
+    if data.is_empty() {
+        data.push(T::default());
+    }
+    let (buffer, upload) = ImmutableBuffer::from_iter(data.into_iter(), BufferUsage::all(), queue.clone()).unwrap();
+    upload.then_signal_fence_and_flush().unwrap().wait(None).unwrap();
+    buffer
+}
+
+/// A device-local buffer holding `data`, which can be updated later with `upload`.
+/// Without data it still has room for one element, like `immutable`.
+fn device_local<T>(device: &Arc<Device>, queue: &Arc<Queue>, data: Vec<T>) -> Arc<DeviceLocalBuffer<[T]>>
+where
+    T: Copy + Send + Sync + 'static,
+    [T]: Content,
+{
+    let buffer = DeviceLocalBuffer::array(device.clone(), data.len().max(1), BufferUsage::all(), device.active_queue_families()).unwrap();
+    if data.is_empty() {
+        return buffer;
+    }
+    let cb = upload(AutoCommandBufferBuilder::new(device.clone(), queue.family()).unwrap(), device, &buffer, &data, 0..data.len())
+        .build().unwrap();
+    cb.execute(queue.clone()).unwrap()
+        .then_signal_fence_and_flush().unwrap()
+        .wait(None).unwrap();
+    buffer
+}
+
+/// Records a copy of `data[range]` to the same range of `buffer`, through a staging buffer
+fn upload<T>(builder: AutoCommandBufferBuilder, device: &Arc<Device>, buffer: &Arc<DeviceLocalBuffer<[T]>>, data: &[T], range: Range<usize>) -> AutoCommandBufferBuilder
+where
+    T: Copy + Send + Sync + 'static,
+    [T]: Content,
+{
+    // a staging buffer can't be empty either
+    if range.start == range.end {
+        return builder;
+    }
+    let staging = CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::transfer_source(), data[range.clone()].iter().cloned()).unwrap();
+    let destination = buffer.clone().into_buffer_slice().slice(range).unwrap();
+    builder.copy_buffer(staging, destination).unwrap()
+}
+
+/// Writes the average of the accumulated samples to an image file
+pub fn save_image(path: &Path, dimensions: [u32; 2], accum: &[[f32;4]], aovs: &[tracer::ty::Aov]) {
+    let colors = accum.iter().zip(aovs).map(|(c, aov)| {
+        let scale = 1.0 / tracer::frames(aov);
+        [c[0] * scale, c[1] * scale, c[2] * scale]
+    });
+    save_png(path, dimensions, colors);
+}
+/// Like `save_image`, but runs the denoiser over the samples first
+pub fn save_denoised(path: &Path, dimensions: [u32; 2], accum: &[[f32;4]], aovs: &[tracer::ty::Aov]) {
+    let colors = denoise::denoise(accum, aovs, dimensions);
+    save_png(path, dimensions, colors.into_iter());
+}
+/// Writes the average of the accumulated samples and of the AOVs to a multi-layer EXR file
+pub fn save_exr(path: &Path, dimensions: [u32; 2], accum: &[[f32;4]], aovs: &[tracer::ty::Aov]) {
+    let count = (dimensions[0] * dimensions[1]) as usize;
+    let average = |f: &Fn(usize) -> f32| -> Vec<f32> { (0..count).map(|i| f(i) / tracer::frames(&aovs[i])).collect() };
+
+    let mut channels = Vec::new();
+    for (c, name) in ["R", "G", "B"].iter().enumerate() {
+        channels.push(exr::Channel::float(name, average(&|i| accum[i][c])));
+        channels.push(exr::Channel::float(&format!("albedo.{}", name), average(&|i| aovs[i].albedo[c])));
+        channels.push(exr::Channel::float(&format!("direct.{}", name), average(&|i| aovs[i].direct[c])));
+        channels.push(exr::Channel::float(&format!("indirect.{}", name), average(&|i| aovs[i].indirect[c])));
+    }
+    for (c, name) in ["X", "Y", "Z"].iter().enumerate() {
+        channels.push(exr::Channel::float(&format!("normal.{}", name), average(&|i| aovs[i].normal[c])));
+    }
+    channels.push(exr::Channel::float("depth.Z", average(&|i| aovs[i].albedo[3])));
+    // the id is not summed, the shader stores the bits of the last sample
+    channels.push(exr::Channel::uint("id", (0..count).map(|i| aovs[i].normal[3].to_bits()).collect()));
+
+    exr::write(path, dimensions[0], dimensions[1], channels).expect("failed to write EXR");
+}
+
+fn aov_buffer(device: &Arc<Device>) -> Arc<CpuAccessibleBuffer<[tracer::ty::Aov]>> {
+    CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), (0..512*512).map(|_| tracer::ty::Aov {
+        albedo: [0.; 4],
+        normal: [0.; 4],
+        direct: [0.; 4],
+        indirect: [0.; 4],
+    })).unwrap()
+}
+
+fn save_png<C: Iterator<Item = [f32; 3]>>(path: &Path, dimensions: [u32; 2], colors: C) {
+    let mut pixels = Vec::with_capacity((dimensions[0] * dimensions[1] * 3) as usize);
+    for color in colors.take((dimensions[0] * dimensions[1]) as usize) {
+        for c in &color {
+            pixels.push((c * 255.0).max(0.0).min(255.0) as u8);
+        }
+    }
+    image::save_buffer(path, &pixels, dimensions[0], dimensions[1], image::RGB(8))
+        .expect("failed to write image");
+}
@@ -0,0 +1,114 @@
+use tracer;
+use std::fmt;
+use std::time::Duration;
+
+/// The error estimate is unreliable with only a few samples, so we never stop before this
+const MIN_SAMPLES: u32 = 8;
+/// Below this luminance a pixel counts as black, so it cannot dominate the relative error
+const BLACK: f32 = 1.0e-2;
+
+/// Why the offline renderer stopped taking samples for a frame
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StopReason {
+    /// the mean relative error fell below the target
+    Converged(f32),
+    TimeBudget,
+    SampleCount,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StopReason::Converged(error) => write!(f, "converged to a mean relative error of {:.4}", error),
+            StopReason::TimeBudget => write!(f, "time budget expired"),
+            StopReason::SampleCount => write!(f, "sample count reached"),
+        }
+    }
+}
+
+/// When to stop sampling a frame. Whatever is reached first wins.
+pub struct Criteria {
+    pub target_error: Option<f32>,
+    pub time_budget: Option<Duration>,
+    pub max_samples: u32,
+}
+
+impl Criteria {
+    /// `error` is only computed when a target error was given
+    pub fn check<E: FnOnce() -> f32>(&self, samples: u32, elapsed: Duration, error: E) -> Option<StopReason> {
+        if samples >= self.max_samples {
+            return Some(StopReason::SampleCount);
+        }
+        if let Some(budget) = self.time_budget {
+            if elapsed >= budget {
+                return Some(StopReason::TimeBudget);
+            }
+        }
+        if let Some(target) = self.target_error {
+            if samples >= MIN_SAMPLES {
+                let error = error();
+                if error < target {
+                    return Some(StopReason::Converged(error));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The standard error of the mean luminance of a pixel relative to that mean.
+/// `pixel` holds the sum of its `n` samples in rgb and the sum of their squared luminance in a.
+pub fn relative_error(pixel: &[f32; 4], n: f32) -> f32 {
+    let mean = (0.2126 * pixel[0] + 0.7152 * pixel[1] + 0.0722 * pixel[2]) / n;
+    let variance = (pixel[3] / n - mean * mean).max(0.0);
+    (variance / n).sqrt() / mean.max(BLACK)
+}
+
+/// The relative error averaged over all pixels, each with the samples its AOV counts
+pub fn mean_relative_error(accum: &[[f32; 4]], aovs: &[tracer::ty::Aov]) -> f32 {
+    if accum.is_empty() {
+        return 0.0;
+    }
+    let sum: f32 = accum.iter().zip(aovs).map(|(pixel, aov)| relative_error(pixel, tracer::frames(aov))).sum();
+    sum / accum.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn criteria(target_error: Option<f32>, time_budget: Option<Duration>) -> Criteria {
+        Criteria { target_error, time_budget, max_samples: 64 }
+    }
+
+    #[test]
+    fn waits_for_min_samples() {
+        let criteria = criteria(Some(0.1), None);
+        for samples in 1..MIN_SAMPLES {
+            assert_eq!(criteria.check(samples, Duration::from_secs(0), || panic!("error computed too early")), None);
+        }
+        assert_eq!(criteria.check(MIN_SAMPLES, Duration::from_secs(0), || 0.05), Some(StopReason::Converged(0.05)));
+    }
+
+    #[test]
+    fn keeps_sampling_above_the_target() {
+        let criteria = criteria(Some(0.1), None);
+        assert_eq!(criteria.check(MIN_SAMPLES, Duration::from_secs(0), || 0.2), None);
+    }
+
+    #[test]
+    fn stops_at_the_sample_count_and_time_budget() {
+        let criteria = criteria(None, Some(Duration::from_secs(1)));
+        assert_eq!(criteria.check(64, Duration::from_secs(0), || 1.0), Some(StopReason::SampleCount));
+        assert_eq!(criteria.check(1, Duration::from_secs(2), || 1.0), Some(StopReason::TimeBudget));
+        assert_eq!(criteria.check(1, Duration::from_millis(10), || panic!("no target error")), None);
+    }
+
+    #[test]
+    fn error_of_constant_samples_is_zero() {
+        // four samples of luminance 0.5
+        let pixel = [2.0, 2.0, 2.0, 4.0 * 0.25];
+        assert!(relative_error(&pixel, 4.0) < 1.0e-6);
+        assert_eq!(mean_relative_error(&[], &[]), 0.0);
+    }
+}
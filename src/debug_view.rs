@@ -0,0 +1,72 @@
+use adaptive;
+
+/// What the tracer shows instead of the rendered image.
+/// The discriminants match the `DEBUG_` defines in the shader.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DebugView {
+    None = 0,
+    BvhVisits = 1,
+    TriangleTests = 2,
+    Normals = 3,
+    Albedo = 4,
+    Depth = 5,
+    Uv = 6,
+    Material = 7,
+    PathLength = 8,
+    Variance = 9,
+    SampleDensity = 10,
+}
+
+const VIEWS: [DebugView; 11] = [
+    DebugView::None,
+    DebugView::BvhVisits,
+    DebugView::TriangleTests,
+    DebugView::Normals,
+    DebugView::Albedo,
+    DebugView::Depth,
+    DebugView::Uv,
+    DebugView::Material,
+    DebugView::PathLength,
+    DebugView::Variance,
+    DebugView::SampleDensity,
+];
+
+impl DebugView {
+    pub fn next(self) -> DebugView {
+        VIEWS[(self as usize + 1) % VIEWS.len()]
+    }
+
+    /// The value that maps to the top of the color ramp at first, for views that use one
+    pub fn scale(self) -> f32 {
+        match self {
+            DebugView::BvhVisits => 200.0,
+            DebugView::TriangleTests => 50.0,
+            DebugView::Depth => 50.0,
+            DebugView::PathLength => 16.0,
+            DebugView::Variance => 1.0,
+            DebugView::SampleDensity => adaptive::MAX_SAMPLES as f32,
+            _ => 1.0,
+        }
+    }
+
+    /// A description of the view and of its colors, where `scale` is the value
+    /// at the top of the ramp. [ and ] halve and double it.
+    pub fn legend(self, scale: f32) -> String {
+        let ramp = |what: &str| {
+            format!("{}: blue = 0, red = {} or more ([ and ] change the scale)", what, scale)
+        };
+        match self {
+            DebugView::None => "debug view off".to_string(),
+            DebugView::BvhVisits => ramp("BVH nodes visited by the primary ray"),
+            DebugView::TriangleTests => ramp("triangles tested by the primary ray"),
+            DebugView::Normals => "normals: rgb = xyz * 0.5 + 0.5".to_string(),
+            DebugView::Albedo => "albedo: diffuse color of the material".to_string(),
+            DebugView::Depth => ramp("distance to the primary hit"),
+            DebugView::Uv => "uv: rg = barycentric or spherical coordinates".to_string(),
+            DebugView::Material => "material: a random color per material".to_string(),
+            DebugView::PathLength => ramp("number of bounces of the path"),
+            DebugView::Variance => ramp("variance of the luminance of the samples"),
+            DebugView::SampleDensity => ramp("samples per pixel per frame"),
+        }
+    }
+}
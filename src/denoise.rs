@@ -0,0 +1,236 @@
+use tracer;
+use std::sync::Arc;
+use vulkano::buffer::{BufferUsage, CpuBufferPool, DeviceLocalBuffer};
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Device;
+use vulkano::image::traits::ImageViewAccess;
+use vulkano::pipeline::ComputePipeline;
+use vulkano::pipeline::ComputePipelineAbstract;
+
+mod shader {
+    #[derive(VulkanoShader)]
+    #[ty = "compute"]
+    #[path = "shaders/denoise.glsl.comp"]
+    #[allow(dead_code)]
+    struct Dummy;
+}
+
+/// Number of à-trous iterations; the last one has taps 16 pixels apart
+const ITERATIONS: u32 = 5;
+const SIGMA_LUMINANCE: f32 = 4.0;
+const SIGMA_NORMAL: f32 = 128.0;
+const SIGMA_DEPTH: f32 = 1.0;
+const KERNEL: [f32; 3] = [3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Filters the accumulated image into the output image, on the GPU.
+pub struct DenoisePart<I: 'static + ImageViewAccess + Send + Sync> {
+    pipeline: Arc<ComputePipelineAbstract + Send + Sync>,
+    /// the output image of each slot, like the tracer's
+    images: [Arc<I>; 2],
+    params_pool: CpuBufferPool<shader::ty::Params>,
+    /// the iterations read from one and write to the other
+    buffers: [Arc<DeviceLocalBuffer<[[f32; 4]]>>; 2],
+}
+
+impl<I: 'static + ImageViewAccess + Send + Sync> DenoisePart<I> {
+    pub fn new(device: &Arc<Device>, images: [Arc<I>; 2]) -> DenoisePart<I> {
+        let shader = shader::Shader::load(device.clone()).expect("failed to create shader module");
+        let pipeline = Arc::new(
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &())
+                .expect("failed to create compute pipeline"),
+        );
+        let buffer = || {
+            DeviceLocalBuffer::array(device.clone(), 512*512, BufferUsage::all(), device.active_queue_families()).unwrap()
+        };
+
+        DenoisePart {
+            pipeline,
+            images,
+            params_pool: CpuBufferPool::uniform_buffer(device.clone()),
+            buffers: [buffer(), buffer()],
+        }
+    }
+
+    /// `accum` and `aovs` are the buffers the tracer has just written,
+    /// the result goes to the image of `slot`
+    pub fn render(
+        &mut self,
+        mut builder: AutoCommandBufferBuilder,
+        slot: usize,
+        dimensions: [u32; 2],
+        accum: Arc<DeviceLocalBuffer<[[f32; 4]]>>,
+        aovs: Arc<DeviceLocalBuffer<[tracer::ty::Aov]>>,
+    ) -> AutoCommandBufferBuilder {
+        for i in 0..ITERATIONS {
+            let params = shader::ty::Params {
+                step: 1 << i,
+                last: (i + 1 == ITERATIONS) as u32,
+                sigma_luminance: SIGMA_LUMINANCE,
+                sigma_normal: SIGMA_NORMAL,
+                sigma_depth: SIGMA_DEPTH,
+            };
+            let set = Arc::new(
+                PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+                    .add_image(self.images[slot].clone()).unwrap()
+                    .add_buffer(self.params_pool.next(params).unwrap()).unwrap()
+                    .add_buffer(accum.clone()).unwrap()
+                    .add_buffer(aovs.clone()).unwrap()
+                    .add_buffer(self.buffers[(i as usize + 1) % 2].clone()).unwrap()
+                    .add_buffer(self.buffers[i as usize % 2].clone()).unwrap()
+                    .build()
+                    .unwrap(),
+            );
+            builder = builder.dispatch([dimensions[0] / 16, dimensions[1] / 16, 1],
+                                       self.pipeline.clone(),
+                                       set,
+                                       ())
+                .unwrap();
+        }
+        builder
+    }
+}
+
+fn luminance(c: [f32; 3]) -> f32 {
+    0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2]
+}
+
+/// The same filter as the shader, on the CPU, so it can run without a window.
+/// Returns the filtered color of every pixel.
+pub fn denoise(accum: &[[f32; 4]], aovs: &[tracer::ty::Aov], dimensions: [u32; 2]) -> Vec<[f32; 3]> {
+    let (width, height) = (dimensions[0] as i32, dimensions[1] as i32);
+    let count = (width * height) as usize;
+    let frames: Vec<f32> = aovs[..count].iter().map(tracer::frames).collect();
+
+    let albedo: Vec<[f32; 3]> = aovs[..count].iter().zip(&frames)
+        .map(|(a, &n)| [(a.albedo[0] / n).max(1.0e-3), (a.albedo[1] / n).max(1.0e-3), (a.albedo[2] / n).max(1.0e-3)])
+        .collect();
+    let depth: Vec<f32> = aovs[..count].iter().zip(&frames).map(|(a, &n)| a.albedo[3] / n).collect();
+    let normal: Vec<[f32; 3]> = aovs[..count].iter()
+        .map(|a| {
+            let l = (a.normal[0] * a.normal[0] + a.normal[1] * a.normal[1] + a.normal[2] * a.normal[2]).sqrt();
+            if l > 0.0 {
+                [a.normal[0] / l, a.normal[1] / l, a.normal[2] / l]
+            } else {
+                [0.0; 3]
+            }
+        })
+        .collect();
+    // demodulated color and the variance of its luminance
+    let mut color: Vec<[f32; 4]> = (0..count)
+        .map(|i| {
+            let n = frames[i];
+            let mean = [accum[i][0] / n, accum[i][1] / n, accum[i][2] / n];
+            let l = luminance(mean);
+            let variance = (accum[i][3] / n - l * l).max(0.0) / n;
+            [mean[0] / albedo[i][0], mean[1] / albedo[i][1], mean[2] / albedo[i][2], variance]
+        })
+        .collect();
+
+    for iteration in 0..ITERATIONS {
+        let step = 1 << iteration;
+        let mut filtered = vec![[0.0; 4]; count];
+        for py in 0..height {
+            for px in 0..width {
+                let i = (px + py * width) as usize;
+                let center = color[i];
+                let l = luminance([center[0], center[1], center[2]]);
+                let sigma_l = SIGMA_LUMINANCE * center[3].sqrt() + 1.0e-4;
+
+                let mut sum = [0.0; 4];
+                let mut weights = 0.0;
+                for y in -2i32..3 {
+                    for x in -2i32..3 {
+                        let (qx, qy) = (px + x * step, py + y * step);
+                        if qx < 0 || qy < 0 || qx >= width || qy >= height {
+                            continue;
+                        }
+                        let q = (qx + qy * width) as usize;
+                        let c = color[q];
+
+                        let w = if x == 0 && y == 0 {
+                            KERNEL[0] * KERNEL[0]
+                        } else {
+                            let cos = normal[i][0] * normal[q][0] + normal[i][1] * normal[q][1] + normal[i][2] * normal[q][2];
+                            let w_normal = cos.max(0.0).powf(SIGMA_NORMAL);
+                            let w_depth = (-(depth[i] - depth[q]).abs() / (SIGMA_DEPTH * step as f32 + 1.0e-4)).exp();
+                            let w_luminance = (-(l - luminance([c[0], c[1], c[2]])).abs() / sigma_l).exp();
+                            KERNEL[x.abs() as usize] * KERNEL[y.abs() as usize] * w_normal * w_depth * w_luminance
+                        };
+
+                        for (s, v) in sum.iter_mut().zip(&c[..3]) {
+                            *s += v * w;
+                        }
+                        sum[3] += c[3] * w * w;
+                        weights += w;
+                    }
+                }
+                filtered[i] = [sum[0] / weights, sum[1] / weights, sum[2] / weights, sum[3] / (weights * weights)];
+            }
+        }
+        color = filtered;
+    }
+
+    (0..count)
+        .map(|i| [color[i][0] * albedo[i][0], color[i][1] * albedo[i][1], color[i][2] * albedo[i][2]])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIMENSIONS: [u32; 2] = [32, 32];
+
+    /// One sample per pixel: `f` gives the color, normal and depth of pixel x, y.
+    /// The variance is high, so the luminance alone would not stop the filter.
+    fn image<F: Fn(u32, u32) -> (f32, [f32; 3], f32)>(f: F) -> (Vec<[f32; 4]>, Vec<tracer::ty::Aov>) {
+        let mut accum = Vec::new();
+        let mut aovs = Vec::new();
+        for y in 0..DIMENSIONS[1] {
+            for x in 0..DIMENSIONS[0] {
+                let (c, normal, depth) = f(x, y);
+                accum.push([c, c, c, c * c + 4.0]);
+                aovs.push(tracer::ty::Aov {
+                    albedo: [1.0, 1.0, 1.0, depth],
+                    normal: [normal[0], normal[1], normal[2], 0.0],
+                    direct: [0.0, 0.0, 0.0, 1.0],
+                    indirect: [0.0; 4],
+                });
+            }
+        }
+        (accum, aovs)
+    }
+
+    /// The colors left and right of the edge between columns 15 and 16
+    fn across_edge(colors: &[[f32; 3]]) -> (f32, f32) {
+        let row = 16 * DIMENSIONS[0] as usize;
+        (colors[row + 15][0], colors[row + 16][0])
+    }
+
+    #[test]
+    fn flat_image_stays_flat() {
+        let (accum, aovs) = image(|_, _| (0.5, [0.0, 0.0, 1.0], 1.0));
+        for c in denoise(&accum, &aovs, DIMENSIONS) {
+            for v in &c {
+                assert!((v - 0.5).abs() < 1.0e-4, "{:?}", c);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_normal_edge() {
+        let (accum, aovs) = image(|x, _| if x < 16 { (1.0, [1.0, 0.0, 0.0], 1.0) } else { (0.0, [0.0, 0.0, 1.0], 1.0) });
+        let (left, right) = across_edge(&denoise(&accum, &aovs, DIMENSIONS));
+        assert!(left > 0.99, "{}", left);
+        assert!(right < 0.01, "{}", right);
+    }
+
+    #[test]
+    fn keeps_depth_edge() {
+        let (accum, aovs) = image(|x, _| if x < 16 { (1.0, [0.0, 0.0, 1.0], 1.0) } else { (0.0, [0.0, 0.0, 1.0], 100.0) });
+        let (left, right) = across_edge(&denoise(&accum, &aovs, DIMENSIONS));
+        assert!(left > 0.99, "{}", left);
+        assert!(right < 0.01, "{}", right);
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::Arc;
+use vulkano::instance::{Instance, PhysicalDevice, PhysicalDeviceType};
+
+fn type_name(ty: PhysicalDeviceType) -> &'static str {
+    match ty {
+        PhysicalDeviceType::DiscreteGpu => "discrete GPU",
+        PhysicalDeviceType::IntegratedGpu => "integrated GPU",
+        PhysicalDeviceType::VirtualGpu => "virtual GPU",
+        PhysicalDeviceType::Cpu => "CPU",
+        PhysicalDeviceType::Other => "other",
+    }
+}
+
+/// Bytes of memory that are local to the device
+fn device_memory(physical: &PhysicalDevice) -> usize {
+    physical.memory_heaps()
+        .filter(|heap| heap.is_device_local())
+        .map(|heap| heap.size())
+        .sum()
+}
+
+/// How suitable a device is for the tracer: discrete GPUs first, then the most memory.
+/// Devices that cannot both draw and compute get no score at all.
+fn score(physical: &PhysicalDevice) -> Option<u64> {
+    if !physical.queue_families().any(|q| q.supports_graphics() && q.supports_compute()) {
+        return None;
+    }
+    let ty = match physical.ty() {
+        PhysicalDeviceType::DiscreteGpu => 4,
+        PhysicalDeviceType::IntegratedGpu => 3,
+        PhysicalDeviceType::VirtualGpu => 2,
+        PhysicalDeviceType::Cpu => 1,
+        PhysicalDeviceType::Other => 0,
+    };
+    // memory in MiB breaks ties between devices of the same type
+    Some((ty << 32) + (device_memory(physical) >> 20) as u64)
+}
+
+/// Prints every device with its index, for `--device`
+pub fn list(instance: &Arc<Instance>) {
+    for physical in PhysicalDevice::enumerate(instance) {
+        println!(
+            "{}: {} ({}, {} MiB)",
+            physical.index(),
+            physical.name(),
+            type_name(physical.ty()),
+            device_memory(&physical) >> 20
+        );
+    }
+}
+
+/// The device with the given index or name, or the one with the best score when `choice` is None
+pub fn select<'a>(instance: &'a Arc<Instance>, choice: Option<&str>) -> PhysicalDevice<'a> {
+    match choice {
+        Some(choice) => {
+            let found = match choice.parse::<usize>() {
+                Ok(index) => PhysicalDevice::from_index(instance, index),
+                Err(_) => PhysicalDevice::enumerate(instance).find(|p| p.name() == choice),
+            };
+            found.unwrap_or_else(|| panic!("no device {}, see --list-devices", choice))
+        }
+        None => PhysicalDevice::enumerate(instance)
+            .filter_map(|p| score(&p).map(|s| (s, p)))
+            .max_by_key(|&(s, _)| s)
+            .map(|(_, p)| p)
+            .expect("no graphics device"),
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// The pixels of one channel, row by row from the top
+pub enum Pixels {
+    Uint(Vec<u32>),
+    Float(Vec<f32>),
+}
+
+pub struct Channel {
+    /// layers are separated by a dot, e.g. `albedo.R`
+    pub name: String,
+    pub pixels: Pixels,
+}
+
+impl Channel {
+    pub fn float(name: &str, pixels: Vec<f32>) -> Channel {
+        Channel { name: name.to_string(), pixels: Pixels::Float(pixels) }
+    }
+
+    pub fn uint(name: &str, pixels: Vec<u32>) -> Channel {
+        Channel { name: name.to_string(), pixels: Pixels::Uint(pixels) }
+    }
+
+    fn pixel_type(&self) -> u32 {
+        match self.pixels {
+            Pixels::Uint(_) => 0,
+            Pixels::Float(_) => 2,
+        }
+    }
+
+    /// every pixel type we write is 4 bytes
+    fn write_row<W: Write>(&self, out: &mut W, start: usize, width: usize) -> io::Result<()> {
+        for x in start..start + width {
+            let bits = match self.pixels {
+                Pixels::Uint(ref pixels) => pixels[x],
+                Pixels::Float(ref pixels) => pixels[x].to_bits(),
+            };
+            write_u32(out, bits)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_u32<W: Write>(out: &mut W, value: u32) -> io::Result<()> {
+    out.write_all(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8])
+}
+
+fn write_u64<W: Write>(out: &mut W, value: u64) -> io::Result<()> {
+    write_u32(out, value as u32)?;
+    write_u32(out, (value >> 32) as u32)
+}
+
+fn write_attribute<W: Write>(out: &mut W, name: &str, kind: &str, value: &[u8]) -> io::Result<()> {
+    out.write_all(name.as_bytes())?;
+    out.write_all(&[0])?;
+    out.write_all(kind.as_bytes())?;
+    out.write_all(&[0])?;
+    write_u32(out, value.len() as u32)?;
+    out.write_all(value)
+}
+
+/// Writes an uncompressed scanline OpenEXR file with the given channels,
+/// which must all have `width * height` pixels.
+pub fn write(path: &Path, width: u32, height: u32, mut channels: Vec<Channel>) -> io::Result<()> {
+    // readers expect the channels in alphabetical order, in the header as well as in the pixel data
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01]);
+    write_u32(&mut header, 2)?;
+
+    let mut list = Vec::new();
+    for channel in &channels {
+        list.extend_from_slice(channel.name.as_bytes());
+        list.push(0);
+        write_u32(&mut list, channel.pixel_type())?;
+        // pLinear and three reserved bytes
+        list.extend_from_slice(&[0; 4]);
+        // x and y sampling
+        write_u32(&mut list, 1)?;
+        write_u32(&mut list, 1)?;
+    }
+    list.push(0);
+    write_attribute(&mut header, "channels", "chlist", &list)?;
+    write_attribute(&mut header, "compression", "compression", &[0])?;
+
+    let mut window = Vec::new();
+    for &v in &[0, 0, width - 1, height - 1] {
+        write_u32(&mut window, v)?;
+    }
+    write_attribute(&mut header, "dataWindow", "box2i", &window)?;
+    write_attribute(&mut header, "displayWindow", "box2i", &window)?;
+    // increasing y
+    write_attribute(&mut header, "lineOrder", "lineOrder", &[0])?;
+    let mut one = Vec::new();
+    write_u32(&mut one, 1.0f32.to_bits())?;
+    write_attribute(&mut header, "pixelAspectRatio", "float", &one)?;
+    write_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8])?;
+    write_attribute(&mut header, "screenWindowWidth", "float", &one)?;
+    header.push(0);
+
+    // without compression every block is a single scanline of the same size
+    let row_size = channels.len() as u64 * width as u64 * 4;
+    let block_size = 8 + row_size;
+    let first_block = header.len() as u64 + height as u64 * 8;
+
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(&header)?;
+    for y in 0..height as u64 {
+        write_u64(&mut out, first_block + y * block_size)?;
+    }
+    for y in 0..height {
+        write_u32(&mut out, y)?;
+        write_u32(&mut out, row_size as u32)?;
+        for channel in &channels {
+            channel.write_row(&mut out, (y * width) as usize, width as usize)?;
+        }
+    }
+    out.flush()
+}
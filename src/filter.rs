@@ -0,0 +1,166 @@
+use std::f32::consts::PI;
+
+/// Entries of the table the shader samples pixel offsets from
+const TABLE_SIZE: usize = 256;
+/// Steps used to integrate the filter when building the table
+const INTEGRATION_STEPS: usize = 4096;
+
+/// Pixel reconstruction filter. The tracer samples offsets from the center of
+/// the pixel in proportion to the filter, so every sample has the same weight,
+/// except for the sign where the filter is negative.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Filter {
+    Box,
+    Tent,
+    Gaussian,
+    /// Mitchell-Netravali with B = C = 1/3
+    Mitchell,
+    BlackmanHarris,
+}
+
+impl Filter {
+    pub fn from_name(name: &str) -> Option<Filter> {
+        match name {
+            "box" => Some(Filter::Box),
+            "tent" => Some(Filter::Tent),
+            "gaussian" => Some(Filter::Gaussian),
+            "mitchell" => Some(Filter::Mitchell),
+            "blackman-harris" => Some(Filter::BlackmanHarris),
+            _ => None,
+        }
+    }
+
+    /// Radius in pixels; a box of radius 0.5 jitters within the pixel
+    pub fn default_radius(self) -> f32 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell => 2.0,
+            Filter::BlackmanHarris => 2.0,
+        }
+    }
+
+    /// The 1D filter at offset `x`, which lies within `radius`.
+    /// The 2D filter is the product of the filters along both axes.
+    pub fn evaluate(self, x: f32, radius: f32) -> f32 {
+        match self {
+            Filter::Box => 1.0,
+            Filter::Tent => (1.0 - x.abs() / radius).max(0.0),
+            Filter::Gaussian => {
+                let sigma = radius / 3.0;
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                // shifted down so it reaches zero at the radius
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            }
+            Filter::Mitchell => {
+                let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+                let t = 2.0 * x.abs() / radius;
+                if t < 1.0 {
+                    ((12.0 - 9.0 * b - 6.0 * c) * t * t * t
+                        + (-18.0 + 12.0 * b + 6.0 * c) * t * t
+                        + (6.0 - 2.0 * b)) / 6.0
+                } else if t < 2.0 {
+                    ((-b - 6.0 * c) * t * t * t
+                        + (6.0 * b + 30.0 * c) * t * t
+                        + (-12.0 * b - 48.0 * c) * t
+                        + (8.0 * b + 24.0 * c)) / 6.0
+                } else {
+                    0.0
+                }
+            }
+            Filter::BlackmanHarris => {
+                let n = (x + radius) / (2.0 * radius);
+                0.35875 - 0.48829 * (2.0 * PI * n).cos() + 0.14128 * (4.0 * PI * n).cos()
+                    - 0.01168 * (6.0 * PI * n).cos()
+            }
+        }
+    }
+
+    /// The inverse of the cumulative distribution of the absolute value of the filter,
+    /// sampled at `TABLE_SIZE + 1` regular intervals. Each entry holds the offset from
+    /// the center of the pixel, and the weight of samples between it and the next entry.
+    pub fn table(self, radius: f32) -> Vec<[f32; 2]> {
+        let step = 2.0 * radius / INTEGRATION_STEPS as f32;
+        let values: Vec<f32> = (0..INTEGRATION_STEPS)
+            .map(|i| self.evaluate(-radius + (i as f32 + 0.5) * step, radius))
+            .collect();
+        let mut cdf = Vec::with_capacity(INTEGRATION_STEPS + 1);
+        cdf.push(0.0);
+        for v in &values {
+            let last = cdf[cdf.len() - 1];
+            cdf.push(last + v.abs() * step);
+        }
+        let total_abs = cdf[INTEGRATION_STEPS];
+        let total: f32 = values.iter().sum::<f32>() * step;
+        // makes the expected weight of a sample one
+        let scale = total_abs / total;
+
+        let mut offsets = Vec::with_capacity(TABLE_SIZE + 1);
+        let mut cell = 0;
+        for j in 0..TABLE_SIZE + 1 {
+            let target = total_abs * j as f32 / TABLE_SIZE as f32;
+            while cell + 1 < INTEGRATION_STEPS && cdf[cell + 1] < target {
+                cell += 1;
+            }
+            let width = cdf[cell + 1] - cdf[cell];
+            let t = if width > 0.0 { ((target - cdf[cell]) / width).min(1.0) } else { 0.0 };
+            offsets.push(-radius + (cell as f32 + t) * step);
+        }
+
+        (0..TABLE_SIZE + 1)
+            .map(|j| {
+                let end = offsets[(j + 1).min(TABLE_SIZE)];
+                let middle = 0.5 * (offsets[j] + end);
+                let sign = if self.evaluate(middle, radius) < 0.0 { -1.0 } else { 1.0 };
+                [offsets[j], sign * scale]
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [Filter; 5] = [Filter::Box, Filter::Tent, Filter::Gaussian, Filter::Mitchell, Filter::BlackmanHarris];
+
+    #[test]
+    fn table_offsets_are_monotonic() {
+        for &filter in &FILTERS {
+            let radius = filter.default_radius();
+            let table = filter.table(radius);
+            assert_eq!(table.len(), TABLE_SIZE + 1);
+            assert!((table[0][0] + radius).abs() < 1.0e-3, "{:?} starts at {}", filter, table[0][0]);
+            assert!((table[TABLE_SIZE][0] - radius).abs() < 1.0e-3, "{:?} ends at {}", filter, table[TABLE_SIZE][0]);
+            for pair in table.windows(2) {
+                assert!(pair[0][0] <= pair[1][0], "{:?} goes back from {} to {}", filter, pair[0][0], pair[1][0]);
+            }
+        }
+    }
+
+    #[test]
+    fn positive_filters_have_unit_weights() {
+        for &filter in &[Filter::Box, Filter::Tent, Filter::Gaussian] {
+            for entry in filter.table(filter.default_radius()) {
+                assert!((entry[1] - 1.0).abs() < 1.0e-3, "{:?} has weight {}", filter, entry[1]);
+            }
+        }
+    }
+
+    #[test]
+    fn box_table_is_uniform() {
+        let table = Filter::Box.table(0.5);
+        for (j, entry) in table.iter().enumerate() {
+            let expected = -0.5 + j as f32 / TABLE_SIZE as f32;
+            assert!((entry[0] - expected).abs() < 1.0e-3, "entry {} is at {}", j, entry[0]);
+        }
+    }
+
+    #[test]
+    fn mitchell_has_negative_lobes() {
+        let table = Filter::Mitchell.table(2.0);
+        assert!(table[1][1] < 0.0);
+        assert!(table[TABLE_SIZE / 2][1] > 0.0);
+    }
+}
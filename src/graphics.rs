@@ -7,18 +7,21 @@ use std::marker::{Sync, Send};
 use std::mem;
 
 use types::Vec2;
+use logging::VULKAN;
 
 pub struct GraphicsPart {
     pub dimensions: [u32; 2],
     pub swapchain: Arc<vulkano::swapchain::Swapchain>,
     pub recreate_swapchain: bool,
     pub images: Vec<Arc<vulkano::image::swapchain::SwapchainImage>>,
-    pub texture: Arc<vulkano::image::StorageImage<vulkano::format::R8G8B8A8Unorm>>,
+    /// the images the tracer writes, one per slot, so it can trace into one while the other is drawn
+    pub textures: [Arc<vulkano::image::StorageImage<vulkano::format::R8G8B8A8Unorm>>; 2],
     pipeline: Arc<vulkano::pipeline::GraphicsPipeline<
         vulkano::pipeline::vertex::SingleBufferDefinition<Vec2>,
         Box<vulkano::descriptor::PipelineLayoutAbstract + Sync + Send>,
         Arc<vulkano::framebuffer::RenderPassAbstract + Send + Sync>>>,
-    set: Arc<descriptor_set::DescriptorSet + Send +  Sync>,
+    /// samples the texture of the same slot
+    sets: [Arc<descriptor_set::DescriptorSet + Send +  Sync>; 2],
     renderpass: Arc<vulkano::framebuffer::RenderPassAbstract + Send + Sync>,
     framebuffers: Option<
             Vec<Arc<vulkano::framebuffer::Framebuffer<
@@ -34,6 +37,7 @@ impl GraphicsPart {
         window: &vulkano_win::Window,
         physical: vulkano::instance::PhysicalDevice,
         queue: Arc<vulkano::device::Queue>,
+        compute_family: vulkano::instance::QueueFamily,
     ) -> GraphicsPart {
 
         let vs = vs::Shader::load(device.clone()).expect("failed to create shader module");
@@ -90,23 +94,34 @@ impl GraphicsPart {
                 .unwrap(),
         );
 
-        let texture = vulkano::image::StorageImage::new(
+        // the tracer writes the texture from the compute queue, which may be of another family
+        let mut families = vec![queue.family()];
+        if compute_family.id() != queue.family().id() {
+            families.push(compute_family);
+        }
+        let texture = || vulkano::image::StorageImage::new(
             device.clone(),
             vulkano::image::Dimensions::Dim2d {
                 width: dimensions[0],
                 height: dimensions[1],
             },
             vulkano::format::R8G8B8A8Unorm,
-            Some(queue.family()),
+            families.clone(),
         ).unwrap();
+        let textures = [texture(), texture()];
 
-        let set = Arc::new(
-            descriptor_set::PersistentDescriptorSet::start(pipeline.clone(), 0)
-                .add_sampled_image(texture.clone(), sampler.clone())
-                .unwrap()
-                .build()
-                .unwrap(),
-        );
+        let sets = {
+            let set = |texture: &Arc<vulkano::image::StorageImage<vulkano::format::R8G8B8A8Unorm>>| {
+                Arc::new(
+                    descriptor_set::PersistentDescriptorSet::start(pipeline.clone(), 0)
+                        .add_sampled_image(texture.clone(), sampler.clone())
+                        .unwrap()
+                        .build()
+                        .unwrap(),
+                ) as Arc<descriptor_set::DescriptorSet + Send + Sync>
+            };
+            [set(&textures[0]), set(&textures[1])]
+        };
 
         // Change to ImmutableBuffer
         let vertex_buffer = vulkano::buffer::cpu_access::CpuAccessibleBuffer::from_iter(
@@ -127,10 +142,10 @@ impl GraphicsPart {
             swapchain: swapchain,
             recreate_swapchain: false,
             images: images,
-            set: set,
+            sets: sets,
             renderpass: renderpass,
             framebuffers: None,
-            texture: texture,
+            textures: textures,
             vertex_buffer: vertex_buffer,
         }
     }
@@ -145,7 +160,7 @@ impl GraphicsPart {
             [new_width, new_height]
         };
 
-        println!("{:?}", self.dimensions);
+        debug!(target: VULKAN, "recreating the swapchain at {:?}", self.dimensions);
 
         let (new_swapchain, new_images) =
             match self.swapchain.recreate_with_dimension(self.dimensions) {
@@ -189,10 +204,12 @@ impl GraphicsPart {
         mem::replace(&mut self.framebuffers, new_framebuffers);
     }
 
+    /// Draws the texture of `slot` to swapchain image `image_num`
     pub fn draw(
         &mut self,
         builder: vulkano::command_buffer::AutoCommandBufferBuilder,
         image_num: usize,
+        slot: usize,
     ) -> vulkano::command_buffer::AutoCommandBufferBuilder {
         builder.begin_render_pass(
             self.framebuffers.as_ref().unwrap()[image_num].clone(), false,
@@ -209,7 +226,7 @@ impl GraphicsPart {
                 scissors: None,
             },
             self.vertex_buffer.clone(),
-            self.set.clone(), ())
+            self.sets[slot].clone(), ())
             .unwrap().end_render_pass().unwrap()
     }
 
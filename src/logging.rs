@@ -0,0 +1,25 @@
+//! Categories of log messages, used as the target of the `log` macros.
+//! `RUST_LOG` picks what is shown, e.g. `RUST_LOG=info,perf=debug,camera=trace`;
+//! without it everything at info level and above is.
+
+use env_logger;
+use std::env;
+
+/// loading models and animations
+pub const SCENE: &str = "scene";
+/// statistics of the acceleration structures
+pub const BVH: &str = "bvh";
+/// devices, queues and the swapchain
+pub const VULKAN: &str = "vulkan";
+pub const CAMERA: &str = "camera";
+/// frame rate, timings and image statistics
+pub const PERF: &str = "perf";
+/// keys that toggle rendering modes
+pub const INPUT: &str = "input";
+/// images and reports written to disk
+pub const OUTPUT: &str = "output";
+
+pub fn init() {
+    let filters = env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string());
+    env_logger::Builder::new().parse(&filters).init();
+}
@@ -8,28 +8,61 @@ extern crate vulkano_shader_derive;
 #[macro_use]
 extern crate vulkano;
 extern crate vulkano_win;
+extern crate vk_sys as vk;
 extern crate winit;
 extern crate nalgebra;
 
 extern crate tobj;
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+extern crate image;
 
 mod tracer;
 mod types;
 mod graphics;
 mod compute;
+mod scene;
+mod refit;
+mod options;
+mod animation;
+mod cache;
+mod bvh_stats;
+mod sbvh;
+mod debug_view;
+mod exr;
+mod denoise;
+mod reproject;
+mod convergence;
+mod adaptive;
+mod filter;
+mod devices;
+mod reduce;
+mod timing;
+mod room;
+mod bench;
+mod logging;
 
 use fps_counter::FPSCounter;
-use nalgebra::Vector3;
+use nalgebra::{Matrix4, Vector3};
 use std::collections::HashSet;
 use std::sync::Arc;
 use std::path::Path;
+use std::mem;
+use std::time::{Duration, Instant};
+use options::Options;
+use animation::Animation;
+use convergence::Criteria;
+use debug_view::DebugView;
+use logging::{VULKAN, PERF, INPUT, OUTPUT};
+use scene::Changes;
+use std::fs;
 use vulkano::command_buffer::AutoCommandBufferBuilder;
 use vulkano::device::{Device, DeviceExtensions, Queue};
 use vulkano::instance::{Instance, PhysicalDevice};
-use vulkano::sync::{GpuFuture, now};
+use vulkano::sync::{GpuFuture, FenceSignalFuture, now};
 use vulkano_win::{VkSurfaceBuild, Window};
 use winit::{Event, EventsLoop, WindowBuilder, WindowEvent};
-use bvh::bvh::BVH;
 
 
 fn init_window(instance: Arc<Instance>) -> (EventsLoop, Window) {
@@ -42,7 +75,9 @@ fn init_window(instance: Arc<Instance>) -> (EventsLoop, Window) {
     (events_loop, window)
 }
 
-fn get_device(physical: &PhysicalDevice, window: &Window) -> (Arc<Device>, Arc<Queue>) {
+/// Returns the device with a graphics queue and a compute queue. The compute queue comes
+/// from a dedicated family when there is one, so tracing can overlap with presentation.
+fn get_device(physical: &PhysicalDevice, window: &Window) -> (Arc<Device>, Arc<Queue>, Arc<Queue>) {
     // find a graphics device that supports drawing to a window surface
     let (graphics_device, mut queues) = {
         let graphical_queue_family = physical
@@ -51,6 +86,13 @@ fn get_device(physical: &PhysicalDevice, window: &Window) -> (Arc<Device>, Arc<Q
                 q.supports_graphics() && window.surface().is_supported(q).unwrap_or(false)
             })
             .expect("couldn't find a graphic queue family");
+        let compute_queue_family = physical
+            .queue_families()
+            .find(|&q| q.supports_compute() && !q.supports_graphics());
+        let mut families = vec![(graphical_queue_family, 0.5)];
+        if let Some(family) = compute_queue_family {
+            families.push((family, 0.5));
+        }
 
         // find a device with a swapchain
         let device_ext = DeviceExtensions {
@@ -61,228 +103,156 @@ fn get_device(physical: &PhysicalDevice, window: &Window) -> (Arc<Device>, Arc<Q
             physical.clone(),
             physical.supported_features(),
             &device_ext,
-            [(graphical_queue_family, 0.5)].iter().cloned(),
+            families.into_iter(),
         ).expect("failed to create device")
     };
 
-    // we just take the first queue we found. We should do something proper here in the future
     let graphics_queue = queues.next().unwrap();
-    (graphics_device, graphics_queue)
+    let compute_queue = queues.next().unwrap_or_else(|| graphics_queue.clone());
+    (graphics_device, graphics_queue, compute_queue)
 }
 
 
 
 fn main() {
-    use std::env;
-
-    let obj_file = env::args().nth(1).unwrap();
+    let options = Options::from_args();
+    logging::init();
+    if options.bench {
+        bench::run(&options);
+        return;
+    }
 
 
     // find an instance of Vulkan that allows us to draw to a surface
     let instance = Instance::new(None, &vulkano_win::required_extensions(), None)
         .expect("No instance with surface extension");
 
-    // we select the first graphics device that we find.
-    // TODO Perhaps we should do better
-    let physical = PhysicalDevice::enumerate(&instance).next().expect(
-        "no graphics device",
-    );
+    if options.list_devices {
+        devices::list(&instance);
+        return;
+    }
+    let physical = devices::select(&instance, options.device.as_ref().map(|s| s.as_str()));
+    info!(target: VULKAN, "rendering on {}", physical.name());
     let (mut events_loop, window) = init_window(instance.clone());
-    let (device, queue) = get_device(&physical, &window);
+    let (device, queue, compute_queue) = get_device(&physical, &window);
+    if compute_queue.family().id() != queue.family().id() {
+        info!(target: VULKAN, "tracing on a dedicated compute queue");
+    }
 
     let mut graphics =
-        graphics::GraphicsPart::new(device.clone(), &window, physical.clone(), queue.clone());
-
-
-    let light = 
-        tracer::ty::Triangle {
-
-            p1: [-4.0, 14.9, 5.0],
-            p2: [-4.0, 14.9, 3.0],
-            p3: [4.0,  14.0, 5.0],
-            material: tracer::ty::Material {
-                diffuse: [25., 25., 22.],
-                refl: 0.0,
-                emissive: 1,
-                n: 0.0,
-                _dummy0: [0; 4],
-            },
-            normal: [0.0, -1.0, 0.0],
-            _dummy0: [0;4],
-            _dummy1: [0;4],
-            _dummy2: [0;4],
-            _dummy3: [0;4],
-            _dummy4: [0;4],
-        };
+        graphics::GraphicsPart::new(device.clone(), &window, physical.clone(), queue.clone(), compute_queue.family());
 
-    let (models, _materials) = tobj::load_obj(&Path::new(&obj_file)).unwrap();
-    let mesh = &models[0].mesh;
 
-    let positions: Vec<[f32;3]> = mesh.positions
-        .chunks(3)
-        .map(|i| [i[0], i[1], i[2]])
-        .collect();
-    let normals: Vec<[f32;3]> = mesh.normals
-        .chunks(3)
-        .map(|i| [i[0], i[1], i[2]])
-        .collect();
+    let light = room::light();
 
-    let mut triangles: Vec<tracer::ty::Triangle> = 
-        mesh
-        .indices.chunks(3) 
-        .map(|indices| {
-            let p1 = positions[indices[0] as usize];
-            let p2 = positions[indices[1] as usize];
-            let p3 = positions[indices[2] as usize];
-            tracer::ty::Triangle {
-                p1,
-                p2,
-                p3,
-                normal: if /*normals.len() > 0*/ false { normals[indices[0] as usize] } else { 
-                    let p1 = Vector3::from(p1);
-                    let p2 = Vector3::from(p2);
-                    let p3 = Vector3::from(p3);
-
-                    let e1 = p2 - p1;
-                    let e2 = p3 - p1;
-
-                    let res = e1.cross(&e2).normalize();
-                    [res.x, res.y, res.z]
-
-                },
-                material: tracer::ty::Material {
-                    diffuse: [1.0, 0.71, 0.29],
-                    refl: 0.3,
-                    emissive: 0,
-                    n: 0.0,
-                    _dummy0: [0; 4],
-                },
-                _dummy0: [0;4], _dummy1: [0;4], _dummy2: [0;4], _dummy3: [0;4], _dummy4: [0;4],
-            }
-        }).collect();
-
-
-   // let mut triangles: Vec<tracer::ty::Triangle> = obj.vertices;
-    let bvh = BVH::build(&mut triangles);
-    let nodes = bvh.flatten().into_iter().map(tracer::node_to_node).collect::<Vec<_>>();
-    let node_length = nodes.len();
-
-    //let triangles = vec![ ];
-
-    let num_triangles = triangles.len() as u32;
-
-    let planes = vec![
-        tracer::ty::Plane {
-            normal: [0., 1., 0.],
-            d: 0.0,
-            material: tracer::ty::Material {
-                diffuse: [0.7, 0.7, 0.7],
-                refl: 0.0,
-                emissive: 0,
-                n: 0.0,
-                _dummy0: [0; 4],
-            },
-            _dummy0: [0; 4],
-        },
-        tracer::ty::Plane {
-            normal: [0., -1., 0.],
-            d: 15.0,
-            material: tracer::ty::Material {
-                diffuse: [0.7, 0.7, 0.7],
-                refl: 0.0,
-                emissive: 0,
-                n: 0.0,
-                _dummy0: [0; 4],
-            },
-            _dummy0: [0; 4],
-        },
-        tracer::ty::Plane {
-            normal: [0., 0., 1.],
-            d: 8.,
-            material: tracer::ty::Material {
-                diffuse: [0.7, 0.7, 0.7],
-                refl: 0.0,
-                emissive: 0,
-                n: 0.0,
-                _dummy0: [0; 4],
-            },
-            _dummy0: [0; 4],
-        },
-        tracer::ty::Plane {
-            normal: [1., 0., 0.],
-            d: 8.,
-            material: tracer::ty::Material {
-                diffuse: [0.6, 0.0555, 0.062],
-                refl: 0.0,
-                emissive: 0,
-                n: 0.0,
-                _dummy0: [0; 4],
-            },
-            _dummy0: [0; 4],
-        },
-        tracer::ty::Plane {
-            normal: [-1., 0., 0.],
-            d: 8.,
-            material: tracer::ty::Material {
-                diffuse: [0.0, 0.7, 0.0],
-                refl: 0.0,
-                emissive: 0,
-                n: 0.0,
-                _dummy0: [0; 4],
-            },
-            _dummy0: [0; 4],
-        },
-    ];
+    let mut scene = scene::Scene::new();
+    let mesh = scene.load_mesh(
+        &Path::new(&options.obj_file),
+        room::model_material(),
+        options.sbvh,
+        options.bvh_cache,
+    );
+
+    // lay out the copies on a square grid, spaced by the size of the model
+    let columns = (options.instances as f32).sqrt().ceil() as u32;
+    let spacing = {
+        let aabb = &scene.meshes[mesh].aabb;
+        (aabb.max.x - aabb.min.x).max(aabb.max.z - aabb.min.z) * 1.2
+    };
+    let placements: Vec<Matrix4<f32>> = (0..options.instances)
+        .map(|i| {
+            let x = (i % columns) as f32 - (columns - 1) as f32 / 2.0;
+            let z = -((i / columns) as f32);
+            Matrix4::new_translation(&Vector3::new(x * spacing, 0.0, z * spacing))
+        })
+        .collect();
+    for placement in &placements {
+        scene.add_instance(mesh, *placement);
+    }
 
+    let planes = room::planes();
     let num_planes = planes.len() as u32;
 
-    // TODO we should probably arc this?
-    let spheres = vec![
-        tracer::ty::Sphere {
-            position: [0.5, 2.0, 10.0],
-            radius: 1.5,
-            material: tracer::ty::Material {
-                diffuse: [0.4, 3.0, 0.2],
-                refl: 0.0,
-                emissive: 0,
-                n: 1.66,
-                _dummy0: [0; 4],
-            },
-            _dummy0: [0; 4],
-        },
-    ];
-
-    let num_spheres = spheres.len() as u32;
+    scene.add_sphere(room::sphere());
 
+    scene.build_top_level();
+    if options.bvh_width > 2 {
+        scene.build_wide(options.bvh_width as usize);
+    }
+    if options.bvh_stats {
+        bvh_stats::report(&scene, Path::new("bvh-stats.json")).expect("failed to write BVH statistics");
+    }
+    let top_node_length = scene.top_nodes.len();
+    let num_triangles = scene.triangles.len() as u32;
+
+    // loaded before the tracer, which needs its filter
+    let animation = options.animation.as_ref().map(|path| {
+        Animation::load(Path::new(path)).unwrap_or_else(|e| panic!("{}", e))
+    });
     let mut compute = compute::ComputePart::new(
         &device,
-        graphics.texture.clone(),
-        spheres,
+        graphics.textures.clone(),
         planes,
-        triangles,
-        nodes,
-        queue.family(),
-    );
-
-
-
-    let mut previous_frame_end = Box::new(now(device.clone())) as Box<GpuFuture>;
-
-    let mut camera = tracer::ty::Camera::new(
-        Vector3::new(0.0, 3.0, 20.0),
-        Vector3::new(0.0, 3.0, 1.0),
-        //Vector3::new(2.78, 2.73, -8.0),
-        //Vector3::new(2.73, 2.73, 0.),
-        20.,
+        &scene,
+        options.filter_table(animation.as_ref().and_then(|animation| animation.filter)),
+        &compute_queue,
     );
+    let mut denoiser = denoise::DenoisePart::new(&device, graphics.textures.clone());
+    let mut reprojector = reproject::ReprojectPart::new(&device, graphics.textures.clone());
+    let mut reducer = reduce::ReducePart::new(&device);
+    let mut timer = timing::Timer::new(&device, &compute_queue, &queue, physical.limits().timestamp_period());
+
+
+
+    // traces only wait for the trace before them, never for a draw, so the next
+    // frame is traced while the last one is presented
+    let mut previous_trace = Box::new(now(device.clone())) as Box<GpuFuture>;
+
+    let mut camera = room::camera();
+    // the camera when the shutter closes, only set by animations
+    let mut camera_end: Option<tracer::ty::Camera> = None;
+
+    // in offline mode we render `frames` images, each sampled until `criteria` stops it
+    let offline = animation.is_some() || options.offline;
+    let frames = options.frames.unwrap_or_else(|| match animation {
+        Some(ref animation) => (animation.duration() * options.fps) as u32 + 1,
+        None => 1,
+    });
+    let criteria = Criteria {
+        target_error: options.target_error,
+        time_budget: options.time_budget.map(|s| Duration::from_millis((s * 1000.0) as u64)),
+        max_samples: options.samples,
+    };
+    let mut frame_start = Instant::now();
+    let mut animation_frame = 0;
+    if offline {
+        fs::create_dir_all(&options.output).expect("failed to create output directory");
+    }
 
     let mut keycodes = HashSet::new();
     let mut frame_num = 1;
+    let mut frame_count: u32 = 0;
     let mut fps_counter = FPSCounter::new();
-    let mut debug = 1;
+    // the debug views are only useful interactively
+    let mut debug_view = if offline {
+        DebugView::None
+    } else {
+        DebugView::BvhVisits
+    };
+    // the value at the top of the ramp of the debug view
+    let mut debug_scale = debug_view.scale();
+    let mut denoise = options.denoise;
+    // set when the camera has moved, so the samples of the old view can be reused
+    let mut history: Option<reproject::History> = None;
+    // the fence of the frame submitted last, with its readback slot, its number of samples
+    // and whether it reduced the image to statistics
+    let mut last_frame: Option<(Arc<FenceSignalFuture<Box<GpuFuture>>>, usize, u32, bool)> = None;
+    // the fence of the frame that used each slot last. Its image, readback buffers and
+    // queries may only be written again once it is done
+    let mut slot_fences: [Option<Arc<FenceSignalFuture<Box<GpuFuture>>>>; 2] = [None, None];
 
     loop {
-        previous_frame_end.cleanup_finished();
+        previous_trace.cleanup_finished();
 
         if graphics.recreate_swapchain(&window) {
             continue;
@@ -299,45 +269,197 @@ fn main() {
         };
 
 
-        let cb = {
-            let mut cbb = AutoCommandBufferBuilder::new(device.clone(), queue.family()).unwrap();
-            cbb = compute.render(
-                cbb,
-                graphics.dimensions,
-                tracer::ty::Input {
-                    camera,
-                    num_spheres,
-                    num_planes,
-                    num_triangles,
-                    frame_num,
-                    light,
-                    node_length: node_length as u32,
-                    debug,
-                    _dummy0: [0; 12],
-                },
-            );
-            cbb = graphics.draw(cbb, image_num);
+        if offline && frame_num == 1 {
+            frame_start = Instant::now();
+        }
+        // only wait for the last frame when the CPU has to write buffers it may still be using
+        if offline || options.adaptive {
+            if let Some((ref fence, _, _, _)) = last_frame {
+                fence.wait(None).unwrap();
+            }
+        }
+        // uploaded at the start of the trace
+        let mut changes = Changes::default();
+        if let Some(ref animation) = animation {
+            // every frame of the sequence starts with a clean accumulation buffer,
+            // so only samples from within the shutter interval get mixed
+            if frame_num == 1 {
+                let time = animation_frame as f32 / options.fps;
+                let mut end = camera;
+                animation.apply(time, options.shutter, &mut scene, &mut camera, &mut end);
+                camera_end = Some(end);
+                changes = scene.refit();
+            }
+        } else if options.spin {
+            let angle = 0.02 * frame_count as f32;
+            for (i, placement) in placements.iter().enumerate() {
+                scene.set_transform(i, placement * Matrix4::new_rotation(Vector3::y() * angle));
+            }
+            changes = scene.refit();
+            frame_num = 1;
+        }
+
+        if options.adaptive {
+            // the last frame read back holds the samples so far, unless we start over
+            let (last_slot, samples) = match last_frame {
+                Some((_, slot, samples, _)) if frame_num > 1 => (slot, samples),
+                _ => (0, 0),
+            };
+            compute.update_sample_mask(last_slot, graphics.dimensions, samples);
+        }
+
+        let slot = frame_count as usize % 2;
+        let stats = frame_count % options.stats_interval == 0;
+        let pixels = graphics.dimensions[0] * graphics.dimensions[1];
+        let trace_cb = {
+            let mut cbb = AutoCommandBufferBuilder::new(device.clone(), compute_queue.family()).unwrap();
+            cbb = compute.update(cbb, &scene, &changes);
+            if history.is_some() {
+                cbb = reprojector.save_history(cbb, compute.accum(), compute.aovs());
+            }
+            cbb = compute.reset_ray_count(cbb);
+            // every dispatch adds a sample to every pixel, we only present after the last one
+            for i in 0..options.spp_per_frame {
+                if i > 0 {
+                    frame_num += 1;
+                }
+                cbb = compute.render(
+                    cbb,
+                    slot,
+                    graphics.dimensions,
+                    tracer::ty::Input {
+                        camera,
+                        num_planes,
+                        num_triangles,
+                        frame_num,
+                        light,
+                        top_node_length: top_node_length as u32,
+                        bvh_width: options.bvh_width,
+                        debug_view: debug_view as u32,
+                        debug_scale,
+                        sampler_kind: options.sampler as u32,
+                        camera_end: camera_end.unwrap_or(camera),
+                        _dummy0: [0; 12],
+                        _dummy1: [0; 4],
+                    },
+                );
+                if i == 0 {
+                    if let Some(history) = history.take() {
+                        cbb = reprojector.render(cbb, slot, graphics.dimensions, &camera, &history, compute.accum(), compute.aovs());
+                        frame_num = history.length() + 1;
+                    }
+                }
+            }
+            // the debug views draw straight into the image
+            if denoise && debug_view == DebugView::None {
+                cbb = denoiser.render(cbb, slot, graphics.dimensions, compute.accum(), compute.aovs());
+            }
+            if stats {
+                cbb = reducer.render(cbb, slot, pixels, compute.accum(), compute.aovs());
+                cbb = compute.read_ray_count(cbb, slot);
+            }
+            // only the offline renderer and adaptive sampling read the samples on the CPU
+            if offline || options.adaptive {
+                cbb = compute.readback(cbb, slot);
+            }
             cbb.build().unwrap()
         };
+        let draw_cb = {
+            let cbb = AutoCommandBufferBuilder::new(device.clone(), queue.family()).unwrap();
+            graphics.draw(cbb, image_num, slot).build().unwrap()
+        };
 
-        let future = previous_frame_end
-            .join(acquire_future)
-            .then_execute(queue.clone(), cb)
-            .unwrap()
-            .then_swapchain_present(queue.clone(), graphics.swapchain.clone(), image_num)
-            .then_signal_fence_and_flush()
-            .unwrap();
+        // the frame two before used this slot, it is usually done by now
+        if let Some(ref fence) = slot_fences[slot] {
+            fence.wait(None).unwrap();
+        }
+        // the tracer does not need the swapchain image, only the draw waits for it.
+        // The timestamps go in between, in the order the queues receive the work
+        if let Some(ref timer) = timer {
+            timer.before_trace(&compute_queue, slot);
+        }
+        let traced = Arc::new(
+            previous_trace
+                .then_execute(compute_queue.clone(), trace_cb)
+                .unwrap()
+                .then_signal_fence_and_flush()
+                .unwrap(),
+        );
+        previous_trace = Box::new(traced.clone()) as Box<GpuFuture>;
+        if let Some(ref timer) = timer {
+            timer.after_trace(&compute_queue, slot);
+            timer.before_draw(&queue, slot);
+        }
+        // the draw waits for its own trace only, through a semaphore signalled on the compute queue
+        let future = Box::new(
+            traced
+                .then_signal_semaphore()
+                .join(acquire_future)
+                .then_execute(queue.clone(), draw_cb)
+                .unwrap()
+                .then_swapchain_present(queue.clone(), graphics.swapchain.clone(), image_num),
+        ) as Box<GpuFuture>;
+        // the last timestamp goes before the fence, so the fence covers it
+        future.flush().unwrap();
+        if let Some(ref timer) = timer {
+            timer.after_draw(&queue, slot);
+        }
+        let fence = Arc::new(future.then_signal_fence_and_flush().unwrap());
+        slot_fences[slot] = Some(fence.clone());
 
-        // lets wait until the frame is done, so we can access the buffer
-        // and calculate its energy.
-        future.wait(None).unwrap();
-        let energy = compute.calculate_energy(frame_num);
-        println!("{:?}", energy);
-        println!("{:?}", fps_counter.tick());
+        // offline we need the samples of this frame right away, to decide whether it is done
+        if offline {
+            fence.wait(None).unwrap();
+        }
+        // report on the frame before, which is usually done by now, so the CPU
+        // does not stall on the frame it has just submitted
+        if let Some(ref mut timer) = timer {
+            timer.collect((slot + 1) % 2);
+        }
+        if let Some((fence, slot, _, true)) = mem::replace(&mut last_frame, Some((fence, slot, frame_num, stats))) {
+            fence.wait(None).unwrap();
+            debug!(target: PERF, "{}", reducer.statistics(slot, pixels));
+            if let Some(timings) = timer.as_mut().and_then(|timer| timer.report()) {
+                // rays of the reported frame over the average time of a trace
+                let rays = compute.rays(slot) as f64;
+                debug!(target: PERF, "{}, {:.1} Mrays/s", timings, rays / (timings.trace * 1.0e3));
+            }
+        }
+        let fps = fps_counter.tick();
+        trace!(target: PERF, "{} fps, {} samples per pixel per second", fps, fps * options.spp_per_frame as usize);
 
-        previous_frame_end = Box::new(future) as Box<_>;
+        let stop = if offline {
+            criteria.check(frame_num, frame_start.elapsed(), || {
+                compute.mean_relative_error(slot, graphics.dimensions)
+            })
+        } else {
+            None
+        };
+        if let Some(reason) = stop {
+            info!(target: PERF, "frame {}: {} after {} samples", animation_frame, reason, frame_num);
+            // the frame is done, its readback buffer holds all of its samples
+            let (accum, aovs) = compute.download(slot);
+            let path = Path::new(&options.output).join(format!("frame_{:04}.png", animation_frame));
+            compute::save_image(&path, graphics.dimensions, &accum, &aovs);
+            info!(target: OUTPUT, "wrote {}", path.display());
+            let path = path.with_extension("exr");
+            compute::save_exr(&path, graphics.dimensions, &accum, &aovs);
+            info!(target: OUTPUT, "wrote {}", path.display());
+            if options.denoise {
+                let path = Path::new(&options.output).join(format!("frame_{:04}_denoised.png", animation_frame));
+                compute::save_denoised(&path, graphics.dimensions, &accum, &aovs);
+                info!(target: OUTPUT, "wrote {}", path.display());
+            }
+            animation_frame += 1;
+            if animation_frame >= frames {
+                break;
+            }
+            frame_num = 0;
+        }
 
 
+        // the view this frame was rendered with, the keys below may change it
+        let rendered_view = debug_view;
         // TODO this is probably wrong
         events_loop.poll_events(|event| {
             match event {
@@ -349,7 +471,17 @@ fn main() {
                                 winit::ElementState::Pressed => {
                                     let keycode = input.virtual_keycode.unwrap();
                                     if keycode == VirtualKeyCode::B {
-                                        debug = !debug;
+                                        debug_view = debug_view.next();
+                                        debug_scale = debug_view.scale();
+                                        info!(target: INPUT, "{}", debug_view.legend(debug_scale));
+                                    }
+                                    if keycode == VirtualKeyCode::LBracket || keycode == VirtualKeyCode::RBracket {
+                                        debug_scale *= if keycode == VirtualKeyCode::LBracket { 0.5 } else { 2.0 };
+                                        info!(target: INPUT, "{}", debug_view.legend(debug_scale));
+                                    }
+                                    if keycode == VirtualKeyCode::N {
+                                        denoise = !denoise;
+                                        info!(target: INPUT, "denoiser {}", if denoise { "on" } else { "off" });
                                     }
                                     keycodes.insert(keycode);
                                 }
@@ -368,12 +500,22 @@ fn main() {
         });
 
         use winit::VirtualKeyCode;
-        if !keycodes.is_empty() {
-
+        // the debug views do not accumulate, so after one the samples are stale
+        if debug_view != rendered_view {
+            frame_num = 0;
+        }
+        if !keycodes.is_empty() && !offline {
+            if rendered_view == DebugView::None && debug_view == DebugView::None {
+                history = Some(reproject::History {
+                    camera,
+                    samples: frame_num,
+                });
+            }
             camera.handle_input(&keycodes);
             frame_num = 0;
         }
         frame_num += 1;
+        frame_count += 1;
 
     }
 }
@@ -0,0 +1,195 @@
+use std::env;
+use std::str::FromStr;
+use filter::Filter;
+
+const USAGE: &str = "usage: testit --list-devices\n       \
+                     testit bench [--device INDEX|NAME] [--samples N] [--sbvh] [--bvh-width 2|4|8] [--sampler lcg|pcg|sobol]\n       \
+                     testit <model.obj> [--device INDEX|NAME] [--sbvh] [--no-bvh-cache] [--bvh-stats] \
+                     [--bvh-width 2|4|8] [--instances N] [--spin] \
+                     [--denoise] [--adaptive] [--spp-per-frame N] [--stats-interval N] [--sampler lcg|pcg|sobol] \
+                     [--filter box|tent|gaussian|mitchell|blackman-harris] [--filter-radius R] \
+                     [--animation FILE [--fps F] [--frames N] [--shutter S]] [--offline] \
+                     [--samples N] [--target-error E] [--time-budget S] [--output DIR]";
+
+/// Random number generator of the tracer.
+/// The discriminants match the `SAMPLER_` defines in the shader.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Sampler {
+    Lcg = 0,
+    Pcg = 1,
+    /// Owen scrambled Sobol points
+    Sobol = 2,
+}
+
+/// Command line options.
+pub struct Options {
+    pub obj_file: String,
+    /// print the available devices and exit
+    pub list_devices: bool,
+    /// render the benchmark scenes without a window and write bench.json
+    pub bench: bool,
+    /// index or name of the device to render on, the best scoring one when None
+    pub device: Option<String>,
+    /// build BVHs with spatial splits
+    pub sbvh: bool,
+    /// read and write flattened BVHs from `bvh-cache/`
+    pub bvh_cache: bool,
+    /// print BVH quality statistics and write them to `bvh-stats.json`
+    pub bvh_stats: bool,
+    /// children per BVH node during traversal; 4 and 8 collapse the binary BVH
+    pub bvh_width: u32,
+    /// number of copies of the model, laid out on a square grid
+    pub instances: u32,
+    /// rotate every instance around its vertical axis, to test dynamic scenes
+    pub spin: bool,
+    /// start with the denoiser on, and also write denoised frames in offline mode
+    pub denoise: bool,
+    /// take more samples per frame in noisy parts of the image
+    pub adaptive: bool,
+    /// tracer dispatches recorded for every presented frame, each adding a sample per pixel
+    pub spp_per_frame: u32,
+    /// frames between two reports of the image statistics
+    pub stats_interval: u32,
+    pub sampler: Sampler,
+    /// pixel reconstruction filter, overrides the one of the animation.
+    /// Without either it is a box
+    pub filter: Option<Filter>,
+    /// radius of the filter in pixels, defaults to the one of the animation
+    /// or else to `Filter::default_radius`
+    pub filter_radius: Option<f32>,
+    /// keyframe file. When given, we render an image sequence offline
+    pub animation: Option<String>,
+    pub fps: f32,
+    /// number of frames to render, defaults to the length of the animation
+    pub frames: Option<u32>,
+    /// render a single frame of the static scene offline, as if it were a one frame animation
+    pub offline: bool,
+    /// maximum samples per pixel of every rendered frame
+    pub samples: u32,
+    /// stop sampling a frame once its mean relative error is below this
+    pub target_error: Option<f32>,
+    /// stop sampling a frame after this many seconds
+    pub time_budget: Option<f32>,
+    /// directory the rendered frames are written to
+    pub output: String,
+    /// how long the shutter stays open in seconds, for motion blur
+    pub shutter: f32,
+}
+
+fn parse<T: FromStr>(flag: &str, value: Option<String>) -> T {
+    value.and_then(|v| v.parse().ok()).unwrap_or_else(|| {
+        panic!("{} expects a number\n{}", flag, USAGE)
+    })
+}
+
+impl Options {
+    pub fn from_args() -> Options {
+        let mut args = env::args().skip(1);
+        let mut options = Options {
+            obj_file: String::new(),
+            list_devices: false,
+            bench: false,
+            device: None,
+            sbvh: false,
+            bvh_cache: true,
+            bvh_stats: false,
+            bvh_width: 2,
+            instances: 1,
+            spin: false,
+            denoise: false,
+            adaptive: false,
+            spp_per_frame: 1,
+            stats_interval: 16,
+            sampler: Sampler::Sobol,
+            filter: None,
+            filter_radius: None,
+            animation: None,
+            fps: 24.0,
+            frames: None,
+            offline: false,
+            samples: 64,
+            target_error: None,
+            time_budget: None,
+            output: "frames".to_string(),
+            shutter: 0.0,
+        };
+        let mut obj_file = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--list-devices" => options.list_devices = true,
+                "bench" => options.bench = true,
+                "--device" => options.device = Some(args.next().expect(USAGE)),
+                "--sbvh" => options.sbvh = true,
+                "--no-bvh-cache" => options.bvh_cache = false,
+                "--bvh-stats" => options.bvh_stats = true,
+                "--bvh-width" => options.bvh_width = parse(&arg, args.next()),
+                "--instances" => options.instances = parse(&arg, args.next()),
+                "--spin" => options.spin = true,
+                "--denoise" => options.denoise = true,
+                "--adaptive" => options.adaptive = true,
+                "--spp-per-frame" => options.spp_per_frame = parse(&arg, args.next()),
+                "--stats-interval" => options.stats_interval = parse(&arg, args.next()),
+                "--filter" => {
+                    options.filter = Some(args.next().as_ref().and_then(|s| Filter::from_name(s)).unwrap_or_else(|| {
+                        panic!("--filter expects box, tent, gaussian, mitchell or blackman-harris\n{}", USAGE)
+                    }))
+                }
+                "--filter-radius" => options.filter_radius = Some(parse(&arg, args.next())),
+                "--sampler" => {
+                    options.sampler = match args.next().as_ref().map(|s| s.as_str()) {
+                        Some("lcg") => Sampler::Lcg,
+                        Some("pcg") => Sampler::Pcg,
+                        Some("sobol") => Sampler::Sobol,
+                        _ => panic!("--sampler expects lcg, pcg or sobol\n{}", USAGE),
+                    }
+                }
+                "--animation" => options.animation = Some(args.next().expect(USAGE)),
+                "--fps" => options.fps = parse(&arg, args.next()),
+                "--frames" => options.frames = Some(parse(&arg, args.next())),
+                "--offline" => options.offline = true,
+                "--samples" => options.samples = parse(&arg, args.next()),
+                "--target-error" => options.target_error = Some(parse(&arg, args.next())),
+                "--time-budget" => options.time_budget = Some(parse(&arg, args.next())),
+                "--output" => options.output = args.next().expect(USAGE),
+                "--shutter" => options.shutter = parse(&arg, args.next()),
+                _ => obj_file = Some(arg),
+            }
+        }
+
+        if options.spp_per_frame == 0 {
+            panic!("--spp-per-frame must be at least 1\n{}", USAGE);
+        }
+        if options.samples == 0 {
+            panic!("--samples must be at least 1\n{}", USAGE);
+        }
+        if options.stats_interval == 0 {
+            panic!("--stats-interval must be at least 1\n{}", USAGE);
+        }
+        if ![2, 4, 8].contains(&options.bvh_width) {
+            panic!("--bvh-width must be 2, 4 or 8\n{}", USAGE);
+        }
+        if let Some(radius) = options.filter_radius {
+            if !(radius > 0.0) {
+                panic!("--filter-radius must be positive\n{}", USAGE);
+            }
+        }
+        // these need no model
+        if options.list_devices || options.bench {
+            return options;
+        }
+        options.obj_file = obj_file.expect(USAGE);
+        options
+    }
+
+    /// The table of the reconstruction filter, see `Filter::table`. `scene` is the
+    /// filter and maybe the radius the animation asks for, the command line wins.
+    pub fn filter_table(&self, scene: Option<(Filter, Option<f32>)>) -> Vec<[f32; 2]> {
+        let (filter, radius) = match (self.filter, scene) {
+            (Some(filter), _) => (filter, self.filter_radius),
+            (None, Some((filter, radius))) => (filter, self.filter_radius.or(radius)),
+            (None, None) => (Filter::Box, self.filter_radius),
+        };
+        filter.table(radius.unwrap_or_else(|| filter.default_radius()))
+    }
+}
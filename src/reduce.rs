@@ -0,0 +1,127 @@
+use std::fmt;
+use std::sync::Arc;
+use vulkano::buffer::{BufferUsage, CpuBufferPool, CpuAccessibleBuffer, DeviceLocalBuffer};
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Device;
+use vulkano::pipeline::ComputePipeline;
+use vulkano::pipeline::ComputePipelineAbstract;
+use tracer;
+
+mod shader {
+    #[derive(VulkanoShader)]
+    #[ty = "compute"]
+    #[path = "shaders/reduce.glsl.comp"]
+    #[allow(dead_code)]
+    struct Dummy;
+}
+
+/// Workgroups of the first pass, and so the number of partial results.
+/// The second pass reduces them with a single workgroup.
+const WORKGROUPS: u32 = 64;
+
+/// Statistics of the average image
+#[derive(Copy, Clone, Debug)]
+pub struct Statistics {
+    /// sum of all channels of all pixels
+    pub energy: f32,
+    /// average color of a pixel
+    pub mean: [f32; 3],
+    pub mean_luminance: f32,
+    /// standard deviation of the luminance over the pixels
+    pub luminance_deviation: f32,
+    pub min_luminance: f32,
+    pub max_luminance: f32,
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "energy {:.3}, mean [{:.4}, {:.4}, {:.4}], luminance {:.4} ± {:.4} in {:.4}..{:.4}",
+            self.energy,
+            self.mean[0], self.mean[1], self.mean[2],
+            self.mean_luminance,
+            self.luminance_deviation,
+            self.min_luminance,
+            self.max_luminance
+        )
+    }
+}
+
+/// Reduces the accumulation buffer into `Statistics` on the GPU,
+/// so the CPU only has to read a few numbers.
+pub struct ReducePart {
+    pipeline: Arc<ComputePipelineAbstract + Send + Sync>,
+    params_pool: CpuBufferPool<shader::ty::Params>,
+    partials: Arc<DeviceLocalBuffer<[shader::ty::Stats]>>,
+    /// one result per readback slot, so one can be read while the other is written
+    results: [Arc<CpuAccessibleBuffer<[shader::ty::Stats]>>; 2],
+}
+
+impl ReducePart {
+    pub fn new(device: &Arc<Device>) -> ReducePart {
+        let shader = shader::Shader::load(device.clone()).expect("failed to create shader module");
+        let pipeline = Arc::new(
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &())
+                .expect("failed to create compute pipeline"),
+        );
+        let partials = DeviceLocalBuffer::array(device.clone(), WORKGROUPS as usize, BufferUsage::all(), device.active_queue_families()).unwrap();
+        let result = || {
+            CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), Some(shader::ty::Stats {
+                sum: [0.; 4],
+                moments: [0.; 4],
+            }).into_iter()).unwrap()
+        };
+
+        ReducePart {
+            pipeline,
+            params_pool: CpuBufferPool::uniform_buffer(device.clone()),
+            partials,
+            results: [result(), result()],
+        }
+    }
+
+    /// Records the reduction of the first `count` pixels of `accum` into result `slot`,
+    /// each divided by the frames `aovs` counts for it
+    pub fn render(
+        &mut self,
+        mut builder: AutoCommandBufferBuilder,
+        slot: usize,
+        count: u32,
+        accum: Arc<DeviceLocalBuffer<[[f32; 4]]>>,
+        aovs: Arc<DeviceLocalBuffer<[tracer::ty::Aov]>>,
+    ) -> AutoCommandBufferBuilder {
+        for &(first, count, workgroups) in &[(1, count, WORKGROUPS), (0, WORKGROUPS, 1)] {
+            let set = Arc::new(
+                PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+                    .add_buffer(self.params_pool.next(shader::ty::Params { count, first }).unwrap()).unwrap()
+                    .add_buffer(accum.clone()).unwrap()
+                    .add_buffer(self.partials.clone()).unwrap()
+                    .add_buffer(self.results[slot].clone()).unwrap()
+                    .add_buffer(aovs.clone()).unwrap()
+                    .build()
+                    .unwrap(),
+            );
+            builder = builder.dispatch([workgroups, 1, 1], self.pipeline.clone(), set, ()).unwrap();
+        }
+        builder
+    }
+
+    /// Reads result `slot`, which holds the reduction of `count` pixels.
+    /// The GPU must be done writing it.
+    pub fn statistics(&self, slot: usize, count: u32) -> Statistics {
+        let stats = self.results[slot].read().unwrap()[0];
+        let pixels = count as f32;
+        let mean_luminance = stats.sum[3] / pixels;
+        let mean_square = stats.moments[0] / pixels;
+        Statistics {
+            energy: stats.sum[0] + stats.sum[1] + stats.sum[2],
+            mean: [stats.sum[0] / pixels, stats.sum[1] / pixels, stats.sum[2] / pixels],
+            mean_luminance,
+            luminance_deviation: (mean_square - mean_luminance * mean_luminance).max(0.0).sqrt(),
+            min_luminance: stats.moments[1],
+            max_luminance: stats.moments[2],
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use tracer;
+use bvh::aabb::AABB;
+use std::u32;
+
+/// `entry_index` of a leaf in a rope BVH
+pub const LEAF: u32 = u32::MAX;
+
+/// Indices of the children of the inner node `i`.
+///
+/// The children of a node are found by following the exit indices
+/// from its entry index until we reach its own exit index.
+pub fn children(nodes: &[tracer::ty::Node], i: usize) -> Vec<usize> {
+    let mut children = Vec::new();
+    let mut child = nodes[i].entry_index;
+    while child != nodes[i].exit_index {
+        children.push(child as usize);
+        child = nodes[child as usize].exit_index;
+    }
+    children
+}
+
+/// Recomputes the bounds of a flattened rope BVH after its shapes moved.
+/// The topology is kept, so the tree gets worse the further shapes move.
+///
+/// Nodes are stored depth first, so walking them backwards visits all children
+/// before their parent.
+pub fn refit<F: Fn(&tracer::ty::Node) -> AABB>(nodes: &mut [tracer::ty::Node], shape_aabb: F) {
+    for i in (0..nodes.len()).rev() {
+        let aabb = if nodes[i].entry_index == LEAF {
+            shape_aabb(&nodes[i])
+        } else {
+            children(nodes, i).into_iter().fold(AABB::empty(), |aabb, child| {
+                aabb.join(&tracer::aabb_from_aabb(&nodes[child].aabb))
+            })
+        };
+        nodes[i].aabb = tracer::aabb_to_aabb(aabb);
+    }
+}
@@ -0,0 +1,135 @@
+use tracer;
+use std::sync::Arc;
+use vulkano::buffer::{BufferUsage, CpuBufferPool, DeviceLocalBuffer};
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Device;
+use vulkano::image::traits::ImageViewAccess;
+use vulkano::pipeline::ComputePipeline;
+use vulkano::pipeline::ComputePipelineAbstract;
+
+mod shader {
+    #[derive(VulkanoShader)]
+    #[ty = "compute"]
+    #[path = "shaders/reproject.glsl.comp"]
+    #[allow(dead_code)]
+    struct Dummy;
+}
+
+/// The most samples the reprojected history counts as. Fewer means
+/// less ghosting but more noise while the camera moves.
+pub const MAX_HISTORY: u32 = 16;
+
+fn camera(c: &tracer::ty::Camera) -> shader::ty::Camera {
+    shader::ty::Camera {
+        origin: c.origin,
+        target: c.target,
+        direction: c.direction,
+        p1: c.p1,
+        p2: c.p2,
+        p3: c.p3,
+        right: c.right,
+        up: c.up,
+        focal_distance: c.focal_distance,
+        _dummy0: [0; 4],
+        _dummy1: [0; 4],
+        _dummy2: [0; 4],
+        _dummy3: [0; 4],
+        _dummy4: [0; 4],
+        _dummy5: [0; 4],
+        _dummy6: [0; 4],
+    }
+}
+
+/// The samples accumulated before the camera moved
+#[derive(Copy, Clone)]
+pub struct History {
+    pub camera: tracer::ty::Camera,
+    pub samples: u32,
+}
+
+impl History {
+    /// how many samples the history counts as after reprojection
+    pub fn length(&self) -> u32 {
+        self.samples.min(MAX_HISTORY)
+    }
+}
+
+/// Keeps the accumulated samples when the camera moves.
+pub struct ReprojectPart<I: 'static + ImageViewAccess + Send + Sync> {
+    pipeline: Arc<ComputePipelineAbstract + Send + Sync>,
+    /// the output image of each slot, like the tracer's
+    images: [Arc<I>; 2],
+    params_pool: CpuBufferPool<shader::ty::Params>,
+    history_accum: Arc<DeviceLocalBuffer<[[f32; 4]]>>,
+    history_aovs: Arc<DeviceLocalBuffer<[tracer::ty::Aov]>>,
+}
+
+impl<I: 'static + ImageViewAccess + Send + Sync> ReprojectPart<I> {
+    pub fn new(device: &Arc<Device>, images: [Arc<I>; 2]) -> ReprojectPart<I> {
+        let shader = shader::Shader::load(device.clone()).expect("failed to create shader module");
+        let pipeline = Arc::new(
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &())
+                .expect("failed to create compute pipeline"),
+        );
+        // written by save_history before they are read
+        let history_accum = DeviceLocalBuffer::array(device.clone(), 512*512, BufferUsage::all(), device.active_queue_families()).unwrap();
+        let history_aovs = DeviceLocalBuffer::array(device.clone(), 512*512, BufferUsage::all(), device.active_queue_families()).unwrap();
+
+        ReprojectPart {
+            pipeline,
+            images,
+            params_pool: CpuBufferPool::uniform_buffer(device.clone()),
+            history_accum,
+            history_aovs,
+        }
+    }
+
+    /// Saves the accumulated samples, before the tracer starts over with the new camera
+    pub fn save_history(
+        &self,
+        builder: AutoCommandBufferBuilder,
+        accum: Arc<DeviceLocalBuffer<[[f32; 4]]>>,
+        aovs: Arc<DeviceLocalBuffer<[tracer::ty::Aov]>>,
+    ) -> AutoCommandBufferBuilder {
+        builder
+            .copy_buffer(accum, self.history_accum.clone()).unwrap()
+            .copy_buffer(aovs, self.history_aovs.clone()).unwrap()
+    }
+
+    /// Adds the saved samples to the first sample of the new camera.
+    /// Afterwards a pixel holds up to `history.length() + 1` samples, pixels
+    /// without valid history only the new one. The result goes to the image of `slot`.
+    pub fn render(
+        &mut self,
+        builder: AutoCommandBufferBuilder,
+        slot: usize,
+        dimensions: [u32; 2],
+        current: &tracer::ty::Camera,
+        history: &History,
+        accum: Arc<DeviceLocalBuffer<[[f32; 4]]>>,
+        aovs: Arc<DeviceLocalBuffer<[tracer::ty::Aov]>>,
+    ) -> AutoCommandBufferBuilder {
+        let params = shader::ty::Params {
+            camera: camera(current),
+            previous: camera(&history.camera),
+            history_length: history.length(),
+        };
+        let set = Arc::new(
+            PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+                .add_image(self.images[slot].clone()).unwrap()
+                .add_buffer(self.params_pool.next(params).unwrap()).unwrap()
+                .add_buffer(accum).unwrap()
+                .add_buffer(aovs).unwrap()
+                .add_buffer(self.history_accum.clone()).unwrap()
+                .add_buffer(self.history_aovs.clone()).unwrap()
+                .build()
+                .unwrap(),
+        );
+        builder.dispatch([dimensions[0] / 16, dimensions[1] / 16, 1],
+                         self.pipeline.clone(),
+                         set,
+                         ())
+            .unwrap()
+    }
+}
@@ -0,0 +1,80 @@
+//! The room every scene is rendered in: five walls, a light in the ceiling
+//! and a glass sphere. Without a model this is our Cornell box.
+
+use tracer;
+use nalgebra::Vector3;
+
+pub fn light() -> tracer::ty::Triangle {
+    tracer::triangle(
+        [-4.0, 14.9, 5.0],
+        [-4.0, 14.9, 3.0],
+        [4.0,  14.0, 5.0],
+        [0.0, -1.0, 0.0],
+        tracer::ty::Material {
+            diffuse: [25., 25., 22.],
+            refl: 0.0,
+            emissive: 1,
+            n: 0.0,
+            _dummy0: [0; 4],
+        },
+    )
+}
+
+fn wall(normal: [f32; 3], d: f32, diffuse: [f32; 3]) -> tracer::ty::Plane {
+    tracer::ty::Plane {
+        normal,
+        d,
+        material: tracer::ty::Material {
+            diffuse,
+            refl: 0.0,
+            emissive: 0,
+            n: 0.0,
+            _dummy0: [0; 4],
+        },
+        _dummy0: [0; 4],
+    }
+}
+
+pub fn planes() -> Vec<tracer::ty::Plane> {
+    vec![
+        wall([0., 1., 0.], 0.0, [0.7, 0.7, 0.7]),
+        wall([0., -1., 0.], 15.0, [0.7, 0.7, 0.7]),
+        wall([0., 0., 1.], 8., [0.7, 0.7, 0.7]),
+        wall([1., 0., 0.], 8., [0.6, 0.0555, 0.062]),
+        wall([-1., 0., 0.], 8., [0.0, 0.7, 0.0]),
+    ]
+}
+
+pub fn sphere() -> tracer::ty::Sphere {
+    tracer::ty::Sphere {
+        position: [0.5, 2.0, 10.0],
+        radius: 1.5,
+        material: tracer::ty::Material {
+            diffuse: [0.4, 3.0, 0.2],
+            refl: 0.0,
+            emissive: 0,
+            n: 1.66,
+            _dummy0: [0; 4],
+        },
+        _dummy0: [0; 4],
+    }
+}
+
+/// The material of the loaded model
+pub fn model_material() -> tracer::ty::Material {
+    tracer::ty::Material {
+        diffuse: [1.0, 0.71, 0.29],
+        refl: 0.3,
+        emissive: 0,
+        n: 0.0,
+        _dummy0: [0; 4],
+    }
+}
+
+pub fn camera() -> tracer::ty::Camera {
+    tracer::ty::Camera::new(
+        Vector3::new(0.0, 3.0, 20.0),
+        Vector3::new(0.0, 3.0, 1.0),
+        20.,
+    )
+}
@@ -0,0 +1,474 @@
+//! A BVH builder with spatial splits (SBVH, Stich et al. 2009).
+//!
+//! Besides splitting the set of triangles in two (object splits), a node may
+//! split space with a plane, putting triangles that cross it in both children
+//! with bounds clipped to their side. This keeps the nodes tight around long
+//! thin triangles that would otherwise make every node overlap.
+//!
+//! The result is a rope BVH in the same layout as `BVH::flatten`, so the
+//! shaders do not need to know which builder was used.
+
+use tracer;
+use refit::LEAF;
+use std::f32;
+
+const BINS: usize = 32;
+/// only try spatial splits when children of the best object split overlap
+/// by more than this fraction of the root surface area
+const OVERLAP_THRESHOLD: f32 = 1.0e-5;
+/// the number of references may grow by at most this fraction of the triangles
+const DUPLICATION_BUDGET: f32 = 0.3;
+
+#[derive(Copy, Clone)]
+struct Bounds {
+    min: [f32; 3],
+    max: [f32; 3],
+}
+
+impl Bounds {
+    fn empty() -> Bounds {
+        Bounds {
+            min: [f32::INFINITY; 3],
+            max: [f32::NEG_INFINITY; 3],
+        }
+    }
+
+    fn grow(&mut self, p: &[f32; 3]) {
+        for k in 0..3 {
+            self.min[k] = self.min[k].min(p[k]);
+            self.max[k] = self.max[k].max(p[k]);
+        }
+    }
+
+    fn join(&mut self, other: &Bounds) {
+        self.grow(&other.min);
+        self.grow(&other.max);
+    }
+
+    fn intersection(&self, other: &Bounds) -> Bounds {
+        let mut result = *self;
+        for k in 0..3 {
+            result.min[k] = self.min[k].max(other.min[k]);
+            result.max[k] = self.max[k].min(other.max[k]);
+        }
+        result
+    }
+
+    fn is_empty(&self) -> bool {
+        (0..3).any(|k| self.min[k] > self.max[k])
+    }
+
+    fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let d = [
+            self.max[0] - self.min[0],
+            self.max[1] - self.min[1],
+            self.max[2] - self.min[2],
+        ];
+        2.0 * (d[0] * d[1] + d[1] * d[2] + d[2] * d[0])
+    }
+
+    fn centroid(&self, axis: usize) -> f32 {
+        0.5 * (self.min[axis] + self.max[axis])
+    }
+
+    fn to_aabb(&self) -> tracer::ty::AABB {
+        tracer::ty::AABB {
+            _dummy0: [0; 4],
+            min: self.min,
+            max: self.max,
+        }
+    }
+}
+
+/// A (possibly clipped) part of a triangle
+#[derive(Copy, Clone)]
+struct Reference {
+    triangle: usize,
+    bounds: Bounds,
+}
+
+enum BuildNode {
+    Leaf { bounds: Bounds, triangle: usize },
+    Inner {
+        bounds: Bounds,
+        size: usize,
+        children: Box<(BuildNode, BuildNode)>,
+    },
+}
+
+impl BuildNode {
+    fn size(&self) -> usize {
+        match *self {
+            BuildNode::Leaf { .. } => 1,
+            BuildNode::Inner { size, .. } => size,
+        }
+    }
+}
+
+enum Split {
+    Object { axis: usize, bin: usize, centroids: Bounds },
+    Spatial { axis: usize, position: f32 },
+    Median,
+}
+
+struct Builder<'a> {
+    triangles: &'a [tracer::ty::Triangle],
+    root_area: f32,
+    budget: usize,
+}
+
+/// Bounds of the part of `triangle` between `lo` and `hi` along `axis`
+fn clip(triangle: &tracer::ty::Triangle, axis: usize, lo: f32, hi: f32) -> Bounds {
+    let vertices = [triangle.p1, triangle.p2, triangle.p3];
+    let mut bounds = Bounds::empty();
+    for i in 0..3 {
+        let a = vertices[i];
+        let b = vertices[(i + 1) % 3];
+        if a[axis] >= lo && a[axis] <= hi {
+            bounds.grow(&a);
+        }
+        for &plane in &[lo, hi] {
+            if (a[axis] < plane) != (b[axis] < plane) {
+                let t = (plane - a[axis]) / (b[axis] - a[axis]);
+                let mut p = [0.0; 3];
+                for k in 0..3 {
+                    p[k] = a[k] + t * (b[k] - a[k]);
+                }
+                p[axis] = plane;
+                bounds.grow(&p);
+            }
+        }
+    }
+    bounds
+}
+
+fn bounds_of(references: &[Reference]) -> Bounds {
+    let mut bounds = Bounds::empty();
+    for r in references {
+        bounds.join(&r.bounds);
+    }
+    bounds
+}
+
+impl<'a> Builder<'a> {
+    /// Best binned object split, as (cost, split, overlap of the children)
+    fn object_split(&self, references: &[Reference]) -> Option<(f32, Split, f32)> {
+        let mut centroids = Bounds::empty();
+        for r in references {
+            centroids.grow(&[r.bounds.centroid(0), r.bounds.centroid(1), r.bounds.centroid(2)]);
+        }
+
+        let mut best = None;
+        for axis in 0..3 {
+            let extent = centroids.max[axis] - centroids.min[axis];
+            if extent <= 0.0 {
+                continue;
+            }
+            let mut bins = [(Bounds::empty(), 0); BINS];
+            for r in references {
+                let bin = object_bin(&centroids, axis, r.bounds.centroid(axis));
+                bins[bin].0.join(&r.bounds);
+                bins[bin].1 += 1;
+            }
+            if let Some((cost, bin, overlap)) = sweep(&bins, &bins) {
+                if best.as_ref().map_or(true, |&(c, _, _)| cost < c) {
+                    best = Some((cost, Split::Object { axis, bin, centroids }, overlap));
+                }
+            }
+        }
+        best
+    }
+
+    /// Best spatial split, as (cost, split)
+    fn spatial_split(&self, bounds: &Bounds, references: &[Reference]) -> Option<(f32, Split)> {
+        let mut best = None;
+        for axis in 0..3 {
+            let extent = bounds.max[axis] - bounds.min[axis];
+            if extent <= 0.0 {
+                continue;
+            }
+            let width = extent / BINS as f32;
+            let bin_of = |x: f32| (((x - bounds.min[axis]) / width) as usize).min(BINS - 1);
+
+            let mut entries = [(Bounds::empty(), 0); BINS];
+            let mut exits = [(Bounds::empty(), 0); BINS];
+            for r in references {
+                let first = bin_of(r.bounds.min[axis]);
+                let last = bin_of(r.bounds.max[axis]);
+                for bin in first..last + 1 {
+                    let lo = bounds.min[axis] + bin as f32 * width;
+                    let hi = lo + width;
+                    let part = clip(&self.triangles[r.triangle], axis, lo, hi)
+                        .intersection(&r.bounds);
+                    if !part.is_empty() {
+                        entries[bin].0.join(&part);
+                        exits[bin].0.join(&part);
+                    }
+                }
+                entries[first].1 += 1;
+                exits[last].1 += 1;
+            }
+            if let Some((cost, bin, _)) = sweep(&entries, &exits) {
+                if best.as_ref().map_or(true, |&(c, _)| cost < c) {
+                    let position = bounds.min[axis] + (bin + 1) as f32 * width;
+                    best = Some((cost, Split::Spatial { axis, position }));
+                }
+            }
+        }
+        best
+    }
+
+    fn partition(&mut self, split: &Split, mut references: Vec<Reference>) -> (Vec<Reference>, Vec<Reference>) {
+        match *split {
+            Split::Object { axis, bin, ref centroids } => {
+                references.into_iter().partition(|r| {
+                    object_bin(centroids, axis, r.bounds.centroid(axis)) <= bin
+                })
+            }
+            Split::Spatial { axis, position } => {
+                let mut left = Vec::new();
+                let mut right = Vec::new();
+                for r in references {
+                    if r.bounds.max[axis] <= position {
+                        left.push(r);
+                    } else if r.bounds.min[axis] >= position {
+                        right.push(r);
+                    } else {
+                        let triangle = &self.triangles[r.triangle];
+                        let l = clip(triangle, axis, f32::NEG_INFINITY, position).intersection(&r.bounds);
+                        let h = clip(triangle, axis, position, f32::INFINITY).intersection(&r.bounds);
+                        match (l.is_empty(), h.is_empty()) {
+                            (false, false) => {
+                                left.push(Reference { bounds: l, ..r });
+                                right.push(Reference { bounds: h, ..r });
+                                self.budget = self.budget.saturating_sub(1);
+                            }
+                            (false, true) => left.push(r),
+                            _ => right.push(r),
+                        }
+                    }
+                }
+                (left, right)
+            }
+            Split::Median => {
+                let right = references.split_off(references.len() / 2);
+                (references, right)
+            }
+        }
+    }
+
+    fn build(&mut self, references: Vec<Reference>) -> BuildNode {
+        let bounds = bounds_of(&references);
+        if references.len() == 1 {
+            return BuildNode::Leaf {
+                bounds,
+                triangle: references[0].triangle,
+            };
+        }
+
+        let split = match self.object_split(&references) {
+            Some((cost, object, overlap)) => {
+                let spatial = if overlap / self.root_area > OVERLAP_THRESHOLD && self.budget > 0 {
+                    self.spatial_split(&bounds, &references)
+                } else {
+                    None
+                };
+                match spatial {
+                    Some((spatial_cost, spatial)) => if spatial_cost < cost { spatial } else { object },
+                    None => object,
+                }
+            }
+            None => Split::Median,
+        };
+
+        let (mut left, mut right) = self.partition(&split, references);
+        if left.is_empty() || right.is_empty() {
+            // can happen due to rounding, we have to make progress anyway
+            left.append(&mut right);
+            let (l, r) = self.partition(&Split::Median, left);
+            left = l;
+            right = r;
+        }
+
+        let left = self.build(left);
+        let right = self.build(right);
+        BuildNode::Inner {
+            bounds,
+            size: 1 + left.size() + right.size(),
+            children: Box::new((left, right)),
+        }
+    }
+}
+
+fn object_bin(centroids: &Bounds, axis: usize, centroid: f32) -> usize {
+    let extent = centroids.max[axis] - centroids.min[axis];
+    (((centroid - centroids.min[axis]) / extent * BINS as f32) as usize).min(BINS - 1)
+}
+
+/// Sweeps over the planes between the bins, using the bounds and counts of
+/// `left` for everything left of a plane and of `right` for everything right
+/// of it. Returns the SAH cost, the last bin on the left and the overlap area
+/// of both sides of the cheapest plane.
+fn sweep(left: &[(Bounds, usize); BINS], right: &[(Bounds, usize); BINS]) -> Option<(f32, usize, f32)> {
+    let mut right_bounds = [Bounds::empty(); BINS];
+    let mut right_counts = [0; BINS];
+    let mut bounds = Bounds::empty();
+    let mut count = 0;
+    for bin in (1..BINS).rev() {
+        bounds.join(&right[bin].0);
+        count += right[bin].1;
+        right_bounds[bin] = bounds;
+        right_counts[bin] = count;
+    }
+
+    let mut best: Option<(f32, usize, f32)> = None;
+    let mut bounds = Bounds::empty();
+    let mut count = 0;
+    for bin in 0..BINS - 1 {
+        bounds.join(&left[bin].0);
+        count += left[bin].1;
+        if count == 0 || right_counts[bin + 1] == 0 {
+            continue;
+        }
+        let cost = bounds.surface_area() * count as f32 +
+            right_bounds[bin + 1].surface_area() * right_counts[bin + 1] as f32;
+        if best.map_or(true, |(c, _, _)| cost < c) {
+            let overlap = bounds.intersection(&right_bounds[bin + 1]).surface_area();
+            best = Some((cost, bin, overlap));
+        }
+    }
+    best
+}
+
+fn flatten(node: &BuildNode, exit: u32, nodes: &mut Vec<tracer::ty::Node>) {
+    match *node {
+        BuildNode::Leaf { ref bounds, triangle } => {
+            nodes.push(tracer::ty::Node {
+                _dummy0: [0; 4],
+                aabb: bounds.to_aabb(),
+                entry_index: LEAF,
+                exit_index: exit,
+                shape_index: triangle as u32,
+                shape_type: tracer::SHAPE_INSTANCE,
+            });
+        }
+        BuildNode::Inner { ref bounds, ref children, .. } => {
+            let index = nodes.len() as u32;
+            nodes.push(tracer::ty::Node {
+                _dummy0: [0; 4],
+                aabb: bounds.to_aabb(),
+                entry_index: index + 1,
+                exit_index: exit,
+                shape_index: 0,
+                shape_type: tracer::SHAPE_INSTANCE,
+            });
+            let right = index + 1 + children.0.size() as u32;
+            flatten(&children.0, right, nodes);
+            flatten(&children.1, exit, nodes);
+        }
+    }
+}
+
+/// Builds a rope BVH with spatial splits over `triangles`
+pub fn build(triangles: &[tracer::ty::Triangle]) -> Vec<tracer::ty::Node> {
+    let references: Vec<Reference> = triangles
+        .iter()
+        .enumerate()
+        .map(|(i, t)| {
+            let mut bounds = Bounds::empty();
+            bounds.grow(&t.p1);
+            bounds.grow(&t.p2);
+            bounds.grow(&t.p3);
+            Reference { triangle: i, bounds }
+        })
+        .collect();
+    if references.is_empty() {
+        return Vec::new();
+    }
+
+    let root = bounds_of(&references);
+    let mut builder = Builder {
+        triangles,
+        root_area: root.surface_area().max(f32::MIN_POSITIVE),
+        budget: (triangles.len() as f32 * DUPLICATION_BUDGET) as usize,
+    };
+    let tree = builder.build(references);
+
+    let mut nodes = Vec::with_capacity(tree.size());
+    flatten(&tree, tree.size() as u32, &mut nodes);
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use refit::children;
+    use bvh::aabb::Bounded;
+
+    fn triangle(p1: [f32; 3], p2: [f32; 3], p3: [f32; 3]) -> tracer::ty::Triangle {
+        tracer::triangle(p1, p2, p3, [0.0, 0.0, 1.0], tracer::ty::Material::default())
+    }
+
+    /// A grid of small triangles crossed by long thin diagonal ones,
+    /// which is what spatial splits are for
+    fn triangles() -> Vec<tracer::ty::Triangle> {
+        let mut triangles = Vec::new();
+        for x in 0..10 {
+            for y in 0..10 {
+                let (x, y) = (x as f32, y as f32);
+                triangles.push(triangle([x, y, 0.0], [x + 0.5, y, 0.0], [x, y + 0.5, 0.0]));
+            }
+        }
+        for i in 0..10 {
+            let z = i as f32 * 0.1;
+            triangles.push(triangle([0.0, 0.0, z], [10.0, 10.0, z], [10.0, 10.1, z]));
+        }
+        triangles
+    }
+
+    fn contains(outer: &tracer::ty::AABB, inner: &tracer::ty::AABB) -> bool {
+        (0..3).all(|k| outer.min[k] <= inner.min[k] && outer.max[k] >= inner.max[k])
+    }
+
+    /// Adds the triangles of the leaves below `i` to `covered`, checking
+    /// that every node contains its children along the way
+    fn visit(nodes: &[tracer::ty::Node], i: usize, covered: &mut Vec<bool>) {
+        if nodes[i].entry_index == LEAF {
+            covered[nodes[i].shape_index as usize] = true;
+            return;
+        }
+        for c in children(nodes, i) {
+            assert!(contains(&nodes[i].aabb, &nodes[c].aabb), "node {} does not contain child {}", i, c);
+            visit(nodes, c, covered);
+        }
+    }
+
+    #[test]
+    fn covers_every_triangle() {
+        let triangles = triangles();
+        let nodes = build(&triangles);
+        let mut covered = vec![false; triangles.len()];
+        visit(&nodes, 0, &mut covered);
+        assert!(covered.iter().all(|&c| c), "uncovered triangles: {:?}",
+                covered.iter().enumerate().filter(|&(_, &c)| !c).map(|(i, _)| i).collect::<Vec<_>>());
+        // the rope of the root leads past the end
+        assert_eq!(nodes[0].exit_index as usize, nodes.len());
+    }
+
+    #[test]
+    fn leaves_stay_within_their_triangle() {
+        let triangles = triangles();
+        for node in build(&triangles).iter().filter(|n| n.entry_index == LEAF) {
+            let triangle = tracer::aabb_to_aabb(triangles[node.shape_index as usize].aabb());
+            assert!(contains(&triangle, &node.aabb), "leaf of triangle {} is too big", node.shape_index);
+        }
+    }
+
+    #[test]
+    fn no_triangles_no_nodes() {
+        assert!(build(&[]).is_empty());
+    }
+}
@@ -0,0 +1,487 @@
+use tracer;
+use tobj;
+use refit;
+use cache;
+use sbvh;
+use logging::{SCENE, BVH};
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+use std::ops::Range;
+use nalgebra::{Matrix3, Matrix4, Point3, Rotation3, UnitQuaternion, Vector3, Vector4};
+use bvh::aabb::{AABB, Bounded};
+use bvh::bvh::BVH;
+use bvh::ray::Intersection;
+use bvh::ray::Ray;
+use bvh::bounding_hierarchy::BHShape;
+
+/// A mesh is a range of triangles together with its own (bottom level) BVH.
+/// Node and shape indices inside the BVH are relative to the offsets.
+pub struct Mesh {
+    pub triangle_offset: u32,
+    pub triangle_count: u32,
+    pub node_offset: u32,
+    pub node_length: u32,
+    /// the collapsed BVH, only there when `Scene::build_wide` was called
+    pub wide_node_offset: u32,
+    pub wide_node_length: u32,
+    pub aabb: AABB,
+}
+
+/// A placement of a mesh in the world.
+///
+/// `transform` is the placement when the shutter opens and `transform_end`
+/// when it closes. They only differ for instances that are motion blurred.
+pub struct Instance {
+    pub mesh: usize,
+    pub transform: Matrix4<f32>,
+    pub transform_end: Matrix4<f32>,
+    aabb: AABB,
+}
+
+/// Poses the bounds of a moving instance are taken at while the shutter is open
+const MOTION_STEPS: usize = 16;
+
+/// An affine transform of the form translation * rotation * scale, split up so
+/// that poses can be interpolated without shearing, as the shader does.
+#[derive(Copy, Clone, Debug)]
+pub struct Pose {
+    pub translation: Vector3<f32>,
+    /// unit quaternion as (i, j, k, w)
+    pub rotation: Vector4<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Pose {
+    pub fn from_matrix(m: &Matrix4<f32>) -> Pose {
+        let column = |i: usize| Vector3::new(m[(0, i)], m[(1, i)], m[(2, i)]);
+        let scale = Vector3::new(column(0).norm(), column(1).norm(), column(2).norm());
+        let (x, y, z) = (column(0) / scale.x, column(1) / scale.y, column(2) / scale.z);
+        let rotation = Rotation3::from_matrix_unchecked(Matrix3::new(
+            x.x, y.x, z.x,
+            x.y, y.y, z.y,
+            x.z, y.z, z.z,
+        ));
+        Pose {
+            translation: column(3),
+            rotation: UnitQuaternion::from_rotation_matrix(&rotation).as_ref().coords,
+            scale,
+        }
+    }
+
+    pub fn to_matrix(&self) -> Matrix4<f32> {
+        let (q, t, s) = (&self.rotation, &self.translation, &self.scale);
+        let (x, y, z, w) = (q.x, q.y, q.z, q.w);
+        let r = Matrix3::new(
+            1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - w * z), 2.0 * (x * z + w * y),
+            2.0 * (x * y + w * z), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - w * x),
+            2.0 * (x * z - w * y), 2.0 * (y * z + w * x), 1.0 - 2.0 * (x * x + y * y),
+        );
+        Matrix4::new(
+            r[(0, 0)] * s.x, r[(0, 1)] * s.y, r[(0, 2)] * s.z, t.x,
+            r[(1, 0)] * s.x, r[(1, 1)] * s.y, r[(1, 2)] * s.z, t.y,
+            r[(2, 0)] * s.x, r[(2, 1)] * s.y, r[(2, 2)] * s.z, t.z,
+            0.0, 0.0, 0.0, 1.0,
+        )
+    }
+
+    /// The pose at `t` between `self` (0) and `end` (1), like `instance_transform` in the shader
+    pub fn interpolate(&self, end: &Pose, t: f32) -> Pose {
+        let mut b = end.rotation;
+        let mut d = self.rotation.dot(&b);
+        // take the short way around
+        if d < 0.0 {
+            b = -b;
+            d = -d;
+        }
+        let rotation = if d > 0.9995 {
+            (self.rotation + (b - self.rotation) * t).normalize()
+        } else {
+            let theta = d.acos();
+            (self.rotation * ((1.0 - t) * theta).sin() + b * (t * theta).sin()) / theta.sin()
+        };
+        Pose {
+            translation: self.translation + (end.translation - self.translation) * t,
+            rotation,
+            scale: self.scale + (end.scale - self.scale) * t,
+        }
+    }
+}
+
+/// A leaf of the top level BVH: an instance or a sphere
+struct TopLevelShape {
+    shape_type: u32,
+    index: usize,
+    aabb: AABB,
+    node_index: usize,
+}
+
+/// Ranges of the scene buffers that changed since the last refit,
+/// so that only those have to be uploaded again.
+#[derive(Default, Clone, Debug)]
+pub struct Changes {
+    pub instances: Option<Range<usize>>,
+    pub top_nodes: bool,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_none() && !self.top_nodes
+    }
+}
+
+fn extend(range: &mut Option<Range<usize>>, other: Range<usize>) {
+    *range = Some(match range.take() {
+        Some(r) => r.start.min(other.start)..r.end.max(other.end),
+        None => other,
+    });
+}
+
+/// All bounded geometry of the scene, in a two-level BVH.
+///
+/// The bottom level BVHs of all meshes are concatenated in `nodes`, the top
+/// level BVH is built over the world space bounds of the instances and the
+/// spheres. Its leaves are tagged with the type of shape they refer to.
+pub struct Scene {
+    pub triangles: Vec<tracer::ty::Triangle>,
+    pub nodes: Vec<tracer::ty::Node>,
+    pub wide_nodes: Vec<tracer::ty::WideNode>,
+    /// number of children per node of the wide BVHs, 2 if there are none
+    pub bvh_width: usize,
+    pub meshes: Vec<Mesh>,
+    pub instances: Vec<Instance>,
+    pub spheres: Vec<tracer::ty::Sphere>,
+    pub top_nodes: Vec<tracer::ty::Node>,
+    changes: Changes,
+}
+
+impl Scene {
+    pub fn new() -> Scene {
+        Scene {
+            triangles: Vec::new(),
+            nodes: Vec::new(),
+            wide_nodes: Vec::new(),
+            bvh_width: 2,
+            meshes: Vec::new(),
+            instances: Vec::new(),
+            spheres: Vec::new(),
+            top_nodes: Vec::new(),
+            changes: Changes::default(),
+        }
+    }
+
+    /// Builds a BVH over `triangles` and adds them to the scene. Returns the mesh index.
+    /// With `spatial_splits` the BVH is built by the SBVH builder, which is slower
+    /// but gives better trees for scenes with big overlapping triangles.
+    pub fn add_mesh(&mut self, mut triangles: Vec<tracer::ty::Triangle>, spatial_splits: bool) -> usize {
+        let nodes = if spatial_splits {
+            sbvh::build(&triangles)
+        } else {
+            let bvh = BVH::build(&mut triangles);
+            bvh.flatten().into_iter().map(tracer::node_to_node).collect::<Vec<_>>()
+        };
+        self.add_built_mesh(triangles, nodes)
+    }
+
+    /// Loads an OBJ file as a mesh. When `use_cache` is set, the BVH is read from
+    /// the cache if the file, material and BVH builder are unchanged, and written
+    /// to it otherwise.
+    pub fn load_mesh(
+        &mut self,
+        path: &Path,
+        material: tracer::ty::Material,
+        spatial_splits: bool,
+        use_cache: bool,
+    ) -> usize {
+        let start = Instant::now();
+        // hashing reads the whole file, so only do it for the cache
+        let key = if use_cache {
+            let mut hasher = cache::Hasher::new();
+            hasher.write(&fs::read(path).expect("failed to read OBJ file"));
+            hasher.write(cache::as_bytes(&[material]));
+            hasher.write(&[spatial_splits as u8]);
+            Some(hasher.finish())
+        } else {
+            None
+        };
+
+        if let Some(key) = key {
+            if let Some((triangles, nodes)) = cache::load(key) {
+                info!(target: SCENE, "loaded BVH of {} from cache in {:?}", path.display(), start.elapsed());
+                return self.add_built_mesh(triangles, nodes);
+            }
+        }
+
+        let mesh = self.add_mesh(load_obj(path, material), spatial_splits);
+        info!(target: SCENE, "built BVH of {} in {:?}", path.display(), start.elapsed());
+
+        if let Some(key) = key {
+            let mesh = &self.meshes[mesh];
+            let t = mesh.triangle_offset as usize;
+            let n = mesh.node_offset as usize;
+            if let Err(e) = cache::store(
+                key,
+                &self.triangles[t..t + mesh.triangle_count as usize],
+                &self.nodes[n..n + mesh.node_length as usize],
+            ) {
+                warn!(target: BVH, "failed to write BVH cache: {}", e);
+            }
+        }
+        mesh
+    }
+
+    fn add_built_mesh(
+        &mut self,
+        triangles: Vec<tracer::ty::Triangle>,
+        nodes: Vec<tracer::ty::Node>,
+    ) -> usize {
+        let aabb = triangles.iter().fold(AABB::empty(), |aabb, t| aabb.join(&t.aabb()));
+
+        self.meshes.push(Mesh {
+            triangle_offset: self.triangles.len() as u32,
+            triangle_count: triangles.len() as u32,
+            node_offset: self.nodes.len() as u32,
+            node_length: nodes.len() as u32,
+            wide_node_offset: 0,
+            wide_node_length: 0,
+            aabb,
+        });
+        self.triangles.extend(triangles);
+        self.nodes.extend(nodes);
+        self.meshes.len() - 1
+    }
+
+    /// Collapses the BVH of every mesh into a BVH with `width` children per node.
+    pub fn build_wide(&mut self, width: usize) {
+        self.bvh_width = width;
+        self.wide_nodes.clear();
+        for mesh in &mut self.meshes {
+            let n = mesh.node_offset as usize;
+            let wide = tracer::collapse_to_wide(&self.nodes[n..n + mesh.node_length as usize], width);
+            mesh.wide_node_offset = self.wide_nodes.len() as u32;
+            mesh.wide_node_length = wide.len() as u32;
+            self.wide_nodes.extend(wide);
+        }
+    }
+
+    /// Entries the traversal stack of the wide BVHs needs: every level of inner
+    /// nodes leaves at most `width - 1` siblings of the node it descends into.
+    pub fn wide_stack_size(&self) -> u32 {
+        let depth = self.meshes.iter()
+            .map(|mesh| {
+                let n = mesh.wide_node_offset as usize;
+                tracer::wide_depth(&self.wide_nodes[n..n + mesh.wide_node_length as usize])
+            })
+            .max()
+            .unwrap_or(0);
+        ((self.bvh_width - 1) * depth + 1) as u32
+    }
+
+    /// Places `mesh` in the world using `transform`. Returns the instance index.
+    pub fn add_instance(&mut self, mesh: usize, transform: Matrix4<f32>) -> usize {
+        let aabb = transform_aabb(&transform, &self.meshes[mesh].aabb);
+        self.instances.push(Instance {
+            mesh,
+            transform,
+            transform_end: transform,
+            aabb,
+        });
+        self.instances.len() - 1
+    }
+
+    /// Adds a sphere to the top level BVH. Returns the sphere index.
+    pub fn add_sphere(&mut self, sphere: tracer::ty::Sphere) -> usize {
+        self.spheres.push(sphere);
+        self.spheres.len() - 1
+    }
+
+    /// Builds the top level BVH over all instances and spheres.
+    pub fn build_top_level(&mut self) {
+        let mut shapes: Vec<TopLevelShape> = self.instances
+            .iter()
+            .enumerate()
+            .map(|(index, instance)| (tracer::SHAPE_INSTANCE, index, instance.aabb))
+            .chain(self.spheres.iter().enumerate().map(|(index, sphere)| {
+                (tracer::SHAPE_SPHERE, index, sphere.aabb())
+            }))
+            .map(|(shape_type, index, aabb)| TopLevelShape {
+                shape_type,
+                index,
+                aabb,
+                node_index: 0,
+            })
+            .collect();
+
+        self.top_nodes = if shapes.is_empty() {
+            Vec::new()
+        } else {
+            let bvh = BVH::build(&mut shapes);
+            bvh.flatten()
+                .into_iter()
+                .map(tracer::node_to_node)
+                .map(|mut node| {
+                    if node.entry_index == refit::LEAF {
+                        let shape = &shapes[node.shape_index as usize];
+                        node.shape_type = shape.shape_type;
+                        node.shape_index = shape.index as u32;
+                    }
+                    node
+                })
+                .collect()
+        };
+        self.changes = Changes::default();
+    }
+
+    /// The instances in the layout the shader expects
+    pub fn gpu_instances(&self) -> Vec<tracer::ty::Instance> {
+        self.instances
+            .iter()
+            .map(|instance| instance_to_instance(instance, &self.meshes[instance.mesh]))
+            .collect()
+    }
+
+    /// Moves `instance`. The top level BVH is refitted on the next call to `refit`.
+    pub fn set_transform(&mut self, instance: usize, transform: Matrix4<f32>) {
+        self.set_motion(instance, transform, transform);
+    }
+
+    /// Moves `instance` from `start` to `end` during the shutter interval.
+    /// A rotating instance sweeps outside its bounds at both ends, so it is
+    /// bounded by the union of its bounds at `MOTION_STEPS` poses in between.
+    pub fn set_motion(&mut self, instance: usize, start: Matrix4<f32>, end: Matrix4<f32>) {
+        let aabb = {
+            let mesh = &self.meshes[self.instances[instance].mesh];
+            if start == end {
+                transform_aabb(&start, &mesh.aabb)
+            } else {
+                let (from, to) = (Pose::from_matrix(&start), Pose::from_matrix(&end));
+                (0..MOTION_STEPS + 1).fold(AABB::empty(), |aabb, i| {
+                    let pose = from.interpolate(&to, i as f32 / MOTION_STEPS as f32);
+                    aabb.join(&transform_aabb(&pose.to_matrix(), &mesh.aabb))
+                })
+            }
+        };
+        self.instances[instance].transform = start;
+        self.instances[instance].transform_end = end;
+        self.instances[instance].aabb = aabb;
+        extend(&mut self.changes.instances, instance..instance + 1);
+    }
+
+    /// Refits the top level BVH if any instance moved, and returns what
+    /// changed since the previous refit.
+    pub fn refit(&mut self) -> Changes {
+        if self.changes.instances.is_some() {
+            let (instances, spheres) = (&self.instances, &self.spheres);
+            refit::refit(&mut self.top_nodes, |node| match node.shape_type {
+                tracer::SHAPE_SPHERE => spheres[node.shape_index as usize].aabb(),
+                _ => instances[node.shape_index as usize].aabb,
+            });
+            self.changes.top_nodes = true;
+        }
+        ::std::mem::replace(&mut self.changes, Changes::default())
+    }
+}
+
+impl Bounded for TopLevelShape {
+    fn aabb(&self) -> AABB {
+        self.aabb
+    }
+}
+
+impl BHShape for TopLevelShape {
+    fn set_bh_node_index(&mut self, index: usize) {
+        self.node_index = index;
+    }
+
+    fn bh_node_index(&self) -> usize {
+        self.node_index
+    }
+
+    fn intersect(&self, _ray: &Ray) -> Intersection {
+        Intersection {
+            distance: 0.0,
+            u: 0.0,
+            v: 0.0,
+        }
+    }
+}
+
+/// The upper 4x3 part of an affine transform, as rows
+pub fn affine_rows(m: &Matrix4<f32>) -> [[f32; 4]; 3] {
+    [
+        [m[(0, 0)], m[(0, 1)], m[(0, 2)], m[(0, 3)]],
+        [m[(1, 0)], m[(1, 1)], m[(1, 2)], m[(1, 3)]],
+        [m[(2, 0)], m[(2, 1)], m[(2, 2)], m[(2, 3)]],
+    ]
+}
+
+fn instance_to_instance(instance: &Instance, mesh: &Mesh) -> tracer::ty::Instance {
+    let inverse = instance.transform.try_inverse().expect(
+        "instance transform is not invertible",
+    );
+    let (start, end) = (Pose::from_matrix(&instance.transform), Pose::from_matrix(&instance.transform_end));
+    let moving = (instance.transform != instance.transform_end) as u32 as f32;
+    let vec4 = |v: &Vector3<f32>, w: f32| [v.x, v.y, v.z, w];
+    tracer::ty::Instance {
+        world_to_object: affine_rows(&inverse),
+        object_to_world: affine_rows(&instance.transform),
+        translation: vec4(&start.translation, moving),
+        translation_end: vec4(&end.translation, moving),
+        rotation: start.rotation.into(),
+        rotation_end: end.rotation.into(),
+        scale: vec4(&start.scale, 0.0),
+        scale_end: vec4(&end.scale, 0.0),
+        node_offset: mesh.node_offset,
+        node_length: mesh.node_length,
+        triangle_offset: mesh.triangle_offset,
+        wide_node_offset: mesh.wide_node_offset,
+    }
+}
+
+pub fn transform_point(m: &Matrix4<f32>, p: &Point3<f32>) -> Point3<f32> {
+    Point3::new(
+        m[(0, 0)] * p.x + m[(0, 1)] * p.y + m[(0, 2)] * p.z + m[(0, 3)],
+        m[(1, 0)] * p.x + m[(1, 1)] * p.y + m[(1, 2)] * p.z + m[(1, 3)],
+        m[(2, 0)] * p.x + m[(2, 1)] * p.y + m[(2, 2)] * p.z + m[(2, 3)],
+    )
+}
+
+/// Bounds of the eight transformed corners of `aabb`
+pub fn transform_aabb(m: &Matrix4<f32>, aabb: &AABB) -> AABB {
+    let mut result = AABB::empty();
+    for i in 0..8 {
+        let corner = Point3::new(
+            if i & 1 == 0 { aabb.min.x } else { aabb.max.x },
+            if i & 2 == 0 { aabb.min.y } else { aabb.max.y },
+            if i & 4 == 0 { aabb.min.z } else { aabb.max.z },
+        );
+        result = result.grow(&transform_point(m, &corner));
+    }
+    result
+}
+
+fn triangle_normal(p1: [f32; 3], p2: [f32; 3], p3: [f32; 3]) -> [f32; 3] {
+    let e1 = Vector3::from(p2) - Vector3::from(p1);
+    let e2 = Vector3::from(p3) - Vector3::from(p1);
+    e1.cross(&e2).normalize().into()
+}
+
+/// Loads the first model of an OBJ file as triangles with the given material
+pub fn load_obj(path: &Path, material: tracer::ty::Material) -> Vec<tracer::ty::Triangle> {
+    let (models, _materials) = tobj::load_obj(path).unwrap();
+    let mesh = &models[0].mesh;
+
+    let positions: Vec<[f32;3]> = mesh.positions
+        .chunks(3)
+        .map(|i| [i[0], i[1], i[2]])
+        .collect();
+
+    mesh
+        .indices.chunks(3)
+        .map(|indices| {
+            let p1 = positions[indices[0] as usize];
+            let p2 = positions[indices[1] as usize];
+            let p3 = positions[indices[2] as usize];
+            tracer::triangle(p1, p2, p3, triangle_normal(p1, p2, p3), material)
+        }).collect()
+}
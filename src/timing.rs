@@ -0,0 +1,265 @@
+use std::fmt;
+use std::ptr;
+use std::sync::Arc;
+use vk;
+use logging::PERF;
+use vulkano::device::{Device, Queue};
+use vulkano::{SynchronizedVulkanObject, VulkanObject};
+
+/// Timestamps per frame: before and after the trace on the compute queue,
+/// before and after the draw on the graphics queue
+const TIMESTAMPS: u32 = 4;
+
+/// Average GPU time of the stages of a frame, in milliseconds.
+/// The tracer is a single dispatch, so `trace` is as fine as it gets; a
+/// wavefront tracer would add a timestamp after each of its stages here.
+#[derive(Copy, Clone, Debug)]
+pub struct Timings {
+    pub trace: f64,
+    /// the draw, including its wait for the trace and the swapchain image
+    pub display: f64,
+    /// frames the average was taken over
+    pub frames: u32,
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "trace {:.3} ms, display {:.3} ms over {} frames", self.trace, self.display, self.frames)
+    }
+}
+
+/// Measures the stages of a frame with timestamp queries. vulkano has no queries
+/// yet, and its command buffer builders can't record raw commands, so the
+/// timestamps are written by small command buffers of our own that are submitted
+/// around the ones of vulkano, and ordered by the queues.
+/// Timestamps of different queues can't be compared, so every stage is timed by a
+/// pair on the queue it runs on, and each queue resets its own queries.
+/// Like the readback buffers, there is a set of queries for each of two frames.
+pub struct Timer {
+    device: Arc<Device>,
+    query_pool: vk::QueryPool,
+    command_pools: [vk::CommandPool; 2],
+    /// per slot, resets the trace queries and writes the first timestamp, on the compute queue
+    before_trace: [vk::CommandBuffer; 2],
+    after_trace: [vk::CommandBuffer; 2],
+    /// per slot, the same for the draw on the graphics queue
+    before_draw: [vk::CommandBuffer; 2],
+    after_draw: [vk::CommandBuffer; 2],
+    /// nanoseconds per tick
+    period: f64,
+    /// the bits of a timestamp that count, the counter wraps around above them
+    mask: u64,
+    trace: f64,
+    display: f64,
+    frames: u32,
+}
+
+impl Timer {
+    /// `period` is the timestamp period of the device in nanoseconds.
+    /// None if one of the queues can't write timestamps.
+    pub fn new(device: &Arc<Device>, compute_queue: &Queue, queue: &Queue, period: f32) -> Option<Timer> {
+        let mut bits = 64;
+        for family in &[compute_queue.family(), queue.family()] {
+            match family.timestamp_valid_bits() {
+                Some(valid) => bits = bits.min(valid),
+                None => {
+                    warn!(target: PERF, "queue family {} has no timestamps, GPU timing is disabled", family.id());
+                    return None;
+                }
+            }
+        }
+        let vk = device.pointers();
+        let raw = device.internal_object();
+        let query_pool = unsafe {
+            let info = vk::QueryPoolCreateInfo {
+                sType: vk::STRUCTURE_TYPE_QUERY_POOL_CREATE_INFO,
+                pNext: ptr::null(),
+                flags: 0,
+                queryType: vk::QUERY_TYPE_TIMESTAMP,
+                queryCount: 2 * TIMESTAMPS,
+                pipelineStatistics: 0,
+            };
+            let mut pool = 0;
+            check(vk.CreateQueryPool(raw, &info, ptr::null(), &mut pool));
+            pool
+        };
+        let command_pools = [
+            command_pool(device, compute_queue.family().id()),
+            command_pool(device, queue.family().id()),
+        ];
+
+        let record = |pool, slot: u32, index: u32, reset: bool| unsafe {
+            let info = vk::CommandBufferAllocateInfo {
+                sType: vk::STRUCTURE_TYPE_COMMAND_BUFFER_ALLOCATE_INFO,
+                pNext: ptr::null(),
+                commandPool: pool,
+                level: vk::COMMAND_BUFFER_LEVEL_PRIMARY,
+                commandBufferCount: 1,
+            };
+            let mut cb = 0;
+            check(vk.AllocateCommandBuffers(raw, &info, &mut cb));
+            let begin = vk::CommandBufferBeginInfo {
+                sType: vk::STRUCTURE_TYPE_COMMAND_BUFFER_BEGIN_INFO,
+                pNext: ptr::null(),
+                // resubmitted every other frame, maybe before the last submission ran
+                flags: vk::COMMAND_BUFFER_USAGE_SIMULTANEOUS_USE_BIT,
+                pInheritanceInfo: ptr::null(),
+            };
+            check(vk.BeginCommandBuffer(cb, &begin));
+            if reset {
+                vk.CmdResetQueryPool(cb, query_pool, slot * TIMESTAMPS + index, 2);
+            }
+            // written once all work submitted to the queue before it is done
+            vk.CmdWriteTimestamp(cb, vk::PIPELINE_STAGE_BOTTOM_OF_PIPE_BIT, query_pool, slot * TIMESTAMPS + index);
+            check(vk.EndCommandBuffer(cb));
+            cb
+        };
+
+        Some(Timer {
+            device: device.clone(),
+            query_pool,
+            command_pools,
+            before_trace: [record(command_pools[0], 0, 0, true), record(command_pools[0], 1, 0, true)],
+            after_trace: [record(command_pools[0], 0, 1, false), record(command_pools[0], 1, 1, false)],
+            before_draw: [record(command_pools[1], 0, 2, true), record(command_pools[1], 1, 2, true)],
+            after_draw: [record(command_pools[1], 0, 3, false), record(command_pools[1], 1, 3, false)],
+            period: period as f64,
+            mask: if bits >= 64 { !0 } else { (1 << bits) - 1 },
+            trace: 0.0,
+            display: 0.0,
+            frames: 0,
+        })
+    }
+
+    /// Submit right before the trace, to `compute_queue`.
+    /// The frame that used `slot` before must be done, so wait on its fence first.
+    pub fn before_trace(&self, compute_queue: &Queue, slot: usize) {
+        self.submit(compute_queue, self.before_trace[slot]);
+    }
+
+    /// Submit right after the trace, to `compute_queue`
+    pub fn after_trace(&self, compute_queue: &Queue, slot: usize) {
+        self.submit(compute_queue, self.after_trace[slot]);
+    }
+
+    /// Submit right before the draw, to the graphics queue
+    pub fn before_draw(&self, queue: &Queue, slot: usize) {
+        self.submit(queue, self.before_draw[slot]);
+    }
+
+    /// Submit right after the draw, to the graphics queue, before the fence of the frame
+    pub fn after_draw(&self, queue: &Queue, slot: usize) {
+        self.submit(queue, self.after_draw[slot]);
+    }
+
+    fn submit(&self, queue: &Queue, cb: vk::CommandBuffer) {
+        let info = vk::SubmitInfo {
+            sType: vk::STRUCTURE_TYPE_SUBMIT_INFO,
+            pNext: ptr::null(),
+            waitSemaphoreCount: 0,
+            pWaitSemaphores: ptr::null(),
+            pWaitDstStageMask: ptr::null(),
+            commandBufferCount: 1,
+            pCommandBuffers: &cb,
+            signalSemaphoreCount: 0,
+            pSignalSemaphores: ptr::null(),
+        };
+        unsafe {
+            let queue = queue.internal_object_guard();
+            check(self.device.pointers().QueueSubmit(*queue, 1, &info, 0));
+        }
+    }
+
+    /// Adds the timings of the frame in `slot` to the average, if the GPU is done with it.
+    /// Does not wait, a frame that is not done yet is left out.
+    pub fn collect(&mut self, slot: usize) {
+        let mut ticks = [0u64; TIMESTAMPS as usize];
+        if !self.results(slot, &mut ticks, 0) {
+            return;
+        }
+        self.trace += self.millis(ticks[0], ticks[1]);
+        self.display += self.millis(ticks[2], ticks[3]);
+        self.frames += 1;
+    }
+
+    /// The time of the trace in `slot`, for when there is no draw.
+    /// Waits for `after_trace` to be written.
+    pub fn wait_trace(&self, slot: usize) -> f64 {
+        let mut ticks = [0u64; 2];
+        self.results(slot, &mut ticks, vk::QUERY_RESULT_WAIT_BIT);
+        self.millis(ticks[0], ticks[1])
+    }
+
+    /// Reads the first `ticks.len()` timestamps of `slot`, false if they are not written yet
+    fn results(&self, slot: usize, ticks: &mut [u64], flags: vk::QueryResultFlags) -> bool {
+        let result = unsafe {
+            self.device.pointers().GetQueryPoolResults(
+                self.device.internal_object(),
+                self.query_pool,
+                slot as u32 * TIMESTAMPS,
+                ticks.len() as u32,
+                8 * ticks.len(),
+                ticks.as_mut_ptr() as *mut _,
+                8,
+                vk::QUERY_RESULT_64_BIT | flags,
+            )
+        };
+        result == vk::SUCCESS
+    }
+
+    fn millis(&self, from: u64, to: u64) -> f64 {
+        (to.wrapping_sub(from) & self.mask) as f64 * self.period / 1.0e6
+    }
+
+    /// The average since the last report, if any frame was collected
+    pub fn report(&mut self) -> Option<Timings> {
+        if self.frames == 0 {
+            return None;
+        }
+        let frames = self.frames as f64;
+        let timings = Timings {
+            trace: self.trace / frames,
+            display: self.display / frames,
+            frames: self.frames,
+        };
+        self.trace = 0.0;
+        self.display = 0.0;
+        self.frames = 0;
+        Some(timings)
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let vk = self.device.pointers();
+        let raw = self.device.internal_object();
+        unsafe {
+            // the command buffers may still be running
+            vk.DeviceWaitIdle(raw);
+            for &pool in &self.command_pools {
+                vk.DestroyCommandPool(raw, pool, ptr::null());
+            }
+            vk.DestroyQueryPool(raw, self.query_pool, ptr::null());
+        }
+    }
+}
+
+fn command_pool(device: &Arc<Device>, family: u32) -> vk::CommandPool {
+    let info = vk::CommandPoolCreateInfo {
+        sType: vk::STRUCTURE_TYPE_COMMAND_POOL_CREATE_INFO,
+        pNext: ptr::null(),
+        flags: 0,
+        queueFamilyIndex: family,
+    };
+    let mut pool = 0;
+    unsafe {
+        check(device.pointers().CreateCommandPool(device.internal_object(), &info, ptr::null(), &mut pool));
+    }
+    pool
+}
+
+fn check(result: vk::Result) {
+    if result != vk::SUCCESS {
+        panic!("Vulkan call failed with {}", result);
+    }
+}
@@ -6,6 +6,8 @@ use bvh::ray::Intersection;
 use bvh::ray::Ray;
 use bvh::flat_bvh;
 use bvh::bounding_hierarchy::BHShape;
+use refit::{LEAF, children};
+use logging::CAMERA;
 
 #[derive(VulkanoShader)]
 #[ty = "compute"]
@@ -13,7 +15,7 @@ use bvh::bounding_hierarchy::BHShape;
 #[allow(dead_code)]
 struct Dummy;
 
-fn aabb_to_aabb(aabb: AABB) -> ty::AABB {
+pub fn aabb_to_aabb(aabb: AABB) -> ty::AABB {
     ty::AABB {
         _dummy0: [0; 4],
         min: [aabb.min.x, aabb.min.y, aabb.min.z],
@@ -21,15 +23,256 @@ fn aabb_to_aabb(aabb: AABB) -> ty::AABB {
     }
 }
 
+pub fn aabb_from_aabb(aabb: &ty::AABB) -> AABB {
+    AABB::with_bounds(
+        Point3::new(aabb.min[0], aabb.min[1], aabb.min[2]),
+        Point3::new(aabb.max[0], aabb.max[1], aabb.max[2]),
+    )
+}
+
+/// `shape_type` of leaves in the top level BVH
+pub const SHAPE_INSTANCE: u32 = 0;
+pub const SHAPE_SPHERE: u32 = 1;
+
 pub fn node_to_node(node: flat_bvh::FlatNode) -> ty::Node {
     ty::Node {
         _dummy0: [0; 4],
-        _dummy1: [0; 4],
         aabb: aabb_to_aabb(node.aabb),
         entry_index: node.entry_index,
         exit_index: node.exit_index,
         shape_index: node.shape_index,
+        shape_type: SHAPE_INSTANCE,
+    }
+}
+
+/// The number of frames summed in the pixel of `aov`, which its sums are divided by
+pub fn frames(aov: &ty::Aov) -> f32 {
+    aov.direct[3].max(1.0)
+}
+
+pub fn triangle(p1: [f32; 3], p2: [f32; 3], p3: [f32; 3], normal: [f32; 3], material: ty::Material) -> ty::Triangle {
+    ty::Triangle {
+        p1,
+        p2,
+        p3,
+        normal,
+        material,
+        ..ty::Triangle::default()
+    }
+}
+
+// Placeholders for buffers without data, which the shader never reads
+
+impl Default for ty::AABB {
+    fn default() -> ty::AABB {
+        ty::AABB {
+            _dummy0: [0; 4],
+            min: [0.0; 3],
+            max: [0.0; 3],
+        }
+    }
+}
+
+impl Default for ty::Material {
+    fn default() -> ty::Material {
+        ty::Material {
+            diffuse: [0.0; 3],
+            refl: 0.0,
+            emissive: 0,
+            n: 0.0,
+            _dummy0: [0; 4],
+        }
+    }
+}
+
+impl Default for ty::Sphere {
+    fn default() -> ty::Sphere {
+        ty::Sphere {
+            position: [0.0; 3],
+            radius: 0.0,
+            material: ty::Material::default(),
+            _dummy0: [0; 4],
+        }
+    }
+}
+
+impl Default for ty::Plane {
+    fn default() -> ty::Plane {
+        ty::Plane {
+            normal: [0.0, 1.0, 0.0],
+            d: 0.0,
+            material: ty::Material::default(),
+            _dummy0: [0; 4],
+        }
+    }
+}
+
+impl Default for ty::Triangle {
+    fn default() -> ty::Triangle {
+        ty::Triangle {
+            p1: [0.0; 3],
+            p2: [0.0; 3],
+            p3: [0.0; 3],
+            normal: [0.0, 1.0, 0.0],
+            material: ty::Material::default(),
+            _dummy0: [0; 4],
+            _dummy1: [0; 4],
+            _dummy2: [0; 4],
+            _dummy3: [0; 4],
+            _dummy4: [0; 4],
+        }
+    }
+}
+
+impl Default for ty::Node {
+    /// a single leaf, so a traversal ends right after it
+    fn default() -> ty::Node {
+        ty::Node {
+            _dummy0: [0; 4],
+            aabb: ty::AABB::default(),
+            entry_index: LEAF,
+            exit_index: 1,
+            shape_index: 0,
+            shape_type: SHAPE_INSTANCE,
+        }
+    }
+}
+
+impl Default for ty::WideNode {
+    fn default() -> ty::WideNode {
+        empty_wide_node()
+    }
+}
+
+pub fn empty_wide_node() -> ty::WideNode {
+    ty::WideNode {
+        origin: [0.0; 3],
+        child_count: 0,
+        scale: [0.0; 3],
+        leaf_mask: 0,
+        children: [0; 8],
+        bounds: [0; 16],
+    }
+}
+
+/// `origin + q * scale` the way the shader decodes a bound, with and without
+/// a fused multiply-add, as we don't know which one the driver uses
+fn decode(q: u32, origin: f32, scale: f32) -> [f32; 2] {
+    [origin + q as f32 * scale, (q as f32).mul_add(scale, origin)]
+}
+
+fn quantize(value: f32, origin: f32, scale: f32, round_up: bool) -> u32 {
+    let q = (value - origin) / scale;
+    let q = if round_up { q.ceil() } else { q.floor() };
+    let mut q = q.max(0.0).min(255.0) as u32;
+    // the division and the decoding both round, so step outwards until the
+    // decoded bound really contains `value`
+    if round_up {
+        while q < 255 && decode(q, origin, scale).iter().any(|&d| d < value) {
+            q += 1;
+        }
+    } else {
+        while q > 0 && decode(q, origin, scale).iter().any(|&d| d > value) {
+            q -= 1;
+        }
+    }
+    q
+}
+
+/// Collapses a binary rope BVH into a BVH with up to `width` (at most 8)
+/// children per node. Child bounds are quantized to 8 bits within the
+/// bounds of the node, rounding outwards so they stay conservative.
+///
+/// A node takes over the children of its biggest inner child until it is full.
+/// This only depends on the topology, so a refitted BVH collapses into the
+/// same number of wide nodes.
+pub fn collapse_to_wide(nodes: &[ty::Node], width: usize) -> Vec<ty::WideNode> {
+    assert!(width >= 2 && width <= 8, "BVH width must be between 2 and 8");
+    let mut wide = Vec::new();
+    if nodes.is_empty() {
+        return wide;
     }
+
+    // binary node that a wide node is made from, and where the wide node goes
+    let mut todo = vec![(0, 0)];
+    wide.push(empty_wide_node());
+
+    while let Some((binary, slot)) = todo.pop() {
+        let mut members = if nodes[binary].entry_index == LEAF {
+            vec![binary]
+        } else {
+            children(nodes, binary)
+        };
+        while members.len() < width {
+            let biggest = members
+                .iter()
+                .enumerate()
+                .filter(|&(_, &c)| nodes[c].entry_index != LEAF)
+                .max_by_key(|&(_, &c)| nodes[c].exit_index as usize - c)
+                .map(|(i, _)| i);
+            match biggest {
+                Some(i) => {
+                    let c = members.swap_remove(i);
+                    members.extend(children(nodes, c));
+                }
+                None => break,
+            }
+        }
+
+        let aabb = &nodes[binary].aabb;
+        let mut node = empty_wide_node();
+        node.origin = aabb.min;
+        for k in 0..3 {
+            // slightly bigger than needed, and grown until the top step decodes to
+            // at least the maximum, so rounding can not cut it off
+            let mut scale = ((aabb.max[k] - aabb.min[k]) * (1.0001 / 255.0)).max(1.0e-20);
+            while decode(255, aabb.min[k], scale).iter().any(|&d| d < aabb.max[k]) {
+                scale *= 1.0 + 1.0 / 1024.0;
+            }
+            node.scale[k] = scale;
+        }
+        node.child_count = members.len() as u32;
+        let (origin, scale) = (node.origin, node.scale);
+
+        for (i, &c) in members.iter().enumerate() {
+            if nodes[c].entry_index == LEAF {
+                node.children[i] = nodes[c].shape_index;
+                node.leaf_mask |= 1 << i;
+            } else {
+                node.children[i] = wide.len() as u32;
+                wide.push(empty_wide_node());
+                todo.push((c, node.children[i] as usize));
+            }
+            let child = &nodes[c].aabb;
+            let q = |k: usize, up: bool| {
+                let value = if up { child.max[k] } else { child.min[k] };
+                quantize(value, origin[k], scale[k], up)
+            };
+            node.bounds[2 * i] = q(0, false) | q(1, false) << 8 | q(2, false) << 16 | q(0, true) << 24;
+            node.bounds[2 * i + 1] = q(1, true) | q(2, true) << 8;
+        }
+        wide[slot] = node;
+    }
+    wide
+}
+
+/// Number of levels of inner nodes of a wide BVH, the root being the first
+pub fn wide_depth(wide: &[ty::WideNode]) -> usize {
+    if wide.is_empty() {
+        return 0;
+    }
+    let mut depth = 0;
+    let mut todo = vec![(0, 1)];
+    while let Some((index, level)) = todo.pop() {
+        depth = depth.max(level);
+        let node = &wide[index];
+        for i in 0..node.child_count as usize {
+            if node.leaf_mask & (1 << i) == 0 {
+                todo.push((node.children[i] as usize, level + 1));
+            }
+        }
+    }
+    depth
 }
 
 impl Bounded for ty::Triangle {
@@ -57,6 +300,30 @@ impl BHShape for ty::Triangle {
     }
 }
 
+impl Bounded for ty::Sphere {
+    fn aabb(&self) -> AABB {
+        let center = Point3::new(self.position[0], self.position[1], self.position[2]);
+        let radius = Vector3::new(self.radius, self.radius, self.radius);
+        AABB::with_bounds(center - radius, center + radius)
+    }
+}
+
+impl BHShape for ty::Sphere {
+    fn set_bh_node_index(&mut self, _index: usize) {}
+
+    fn bh_node_index(&self) -> usize {
+        0
+    }
+
+    fn intersect(&self, _ray: &Ray) -> Intersection {
+        Intersection {
+            distance: 0.0,
+            u: 0.0,
+            v: 0.0,
+        }
+    }
+}
+
 impl ty::Camera {
     pub fn new(origin: Vector3<f32>, target: Vector3<f32>, focal_distance: f32) -> ty::Camera {
         let mut camera = ty::Camera::_new(
@@ -114,8 +381,7 @@ impl ty::Camera {
         let right = unit_y.cross(&direction);
         self.right = right.into();
         let up = direction.cross(&right);
-        println!("{:?}", up);
-        println!("{:?}", right);
+        trace!(target: CAMERA, "up {:?}, right {:?}", up, right);
         self.up = up.into();
 
         let c = origin + self.focal_distance * direction;
@@ -206,3 +472,93 @@ impl ty::Camera {
 // jump back to to extend
 // compaction can be done with atomic counter
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bvh::bvh::BVH;
+
+    /// A binary BVH whose leaves have the exact bounds of their triangle,
+    /// unlike the clipped ones of the SBVH
+    fn binary(triangles: &mut Vec<ty::Triangle>) -> Vec<ty::Node> {
+        BVH::build(triangles).flatten().into_iter().map(node_to_node).collect()
+    }
+
+    /// Small triangles scattered by a fixed LCG, so the test is repeatable
+    fn triangles(count: usize) -> Vec<ty::Triangle> {
+        let mut state = 12345u32;
+        let mut next = || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 8) as f32 / (1 << 24) as f32 * 10.0
+        };
+        (0..count)
+            .map(|_| {
+                let p1 = [next(), next(), next()];
+                let p2 = [p1[0] + next() * 0.1, p1[1], p1[2]];
+                let p3 = [p1[0], p1[1] + next() * 0.1, p1[2] + next() * 0.1];
+                triangle(p1, p2, p3, [0.0, 0.0, 1.0], ty::Material::default())
+            })
+            .collect()
+    }
+
+    /// The bounds of child `i` of `node` the way the shader decodes them,
+    /// with both roundings `decode` allows for
+    fn dequantize(node: &ty::WideNode, i: usize) -> ([[f32; 2]; 3], [[f32; 2]; 3]) {
+        let (lo, hi) = (node.bounds[2 * i], node.bounds[2 * i + 1]);
+        let qmin = [lo & 0xff, (lo >> 8) & 0xff, (lo >> 16) & 0xff];
+        let qmax = [lo >> 24, hi & 0xff, (hi >> 8) & 0xff];
+        let mut min = [[0.0; 2]; 3];
+        let mut max = [[0.0; 2]; 3];
+        for k in 0..3 {
+            min[k] = decode(qmin[k], node.origin[k], node.scale[k]);
+            max[k] = decode(qmax[k], node.origin[k], node.scale[k]);
+        }
+        (min, max)
+    }
+
+    /// Checks that the quantized bounds of every child of `wide[index]` contain the
+    /// exact bounds of the triangles below it, and returns those exact bounds.
+    fn exact_bounds(wide: &[ty::WideNode], index: usize, triangles: &[ty::Triangle], leaves: &mut Vec<u32>) -> AABB {
+        let node = &wide[index];
+        let mut aabb = AABB::empty();
+        for i in 0..node.child_count as usize {
+            let child = if node.leaf_mask & (1 << i) != 0 {
+                leaves.push(node.children[i]);
+                triangles[node.children[i] as usize].aabb()
+            } else {
+                exact_bounds(wide, node.children[i] as usize, triangles, leaves)
+            };
+            let (min, max) = dequantize(node, i);
+            for k in 0..3 {
+                assert!(min[k].iter().all(|&m| m <= child.min[k]), "child {} of node {} cut off below", i, index);
+                assert!(max[k].iter().all(|&m| m >= child.max[k]), "child {} of node {} cut off above", i, index);
+            }
+            aabb = aabb.join(&child);
+        }
+        aabb
+    }
+
+    #[test]
+    fn quantized_bounds_are_conservative() {
+        let mut triangles = triangles(500);
+        let nodes = binary(&mut triangles);
+        for &width in &[2, 4, 8] {
+            let wide = collapse_to_wide(&nodes, width);
+            let mut leaves = Vec::new();
+            exact_bounds(&wide, 0, &triangles, &mut leaves);
+            leaves.sort();
+            leaves.dedup();
+            assert_eq!(leaves.len(), triangles.len());
+            assert!(wide.iter().all(|node| node.child_count as usize <= width));
+        }
+    }
+
+    #[test]
+    fn wide_depth_of_a_single_node() {
+        let mut triangles = triangles(3);
+        let wide = collapse_to_wide(&binary(&mut triangles), 4);
+        assert_eq!(wide.len(), 1);
+        assert_eq!(wide_depth(&wide), 1);
+        assert_eq!(wide_depth(&[]), 0);
+    }
+}